@@ -0,0 +1,567 @@
+/// Aggregate the statistics of the parallel streams of a speedtest
+use std::collections::BTreeMap;
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::Duration;
+use eyre::Result;
+use serde::Serialize;
+use crate::{
+    config::TestConfig,
+    cpu,
+    message::StreamReport,
+    pktgenerator::{TcpInfo, Update},
+    units::{self, Format},
+    viewer::Viewer,
+};
+
+/// Report the statistics of a data stream to an aggregator
+/// or to the control connection.
+///
+/// If the reporter is dropped before being done (e.g. on error), the last
+/// statistics are reported as the final results.
+pub struct StreamReporter {
+    reports: Sender<StreamReport>,
+    streamid: u32,
+    /// Reporter of the sending side of the stream
+    sender: bool,
+    last: Update,
+    done: bool,
+}
+
+impl StreamReporter {
+    pub fn new(reports: Sender<StreamReport>, streamid: u32, sender: bool) -> Self {
+        Self {
+            reports,
+            streamid,
+            sender,
+            last: Update::default(),
+            done: false,
+        }
+    }
+
+    fn send(&self, update: &Update, done: bool) {
+        let report = StreamReport {
+            streamid: self.streamid,
+            done,
+            sender: self.sender,
+            update: update.clone(),
+        };
+        // The receiving side may already be gone
+        let _ = self.reports.send(report);
+    }
+
+    pub fn update(&mut self, update: &Update) {
+        self.send(update, false);
+        self.last = update.clone();
+    }
+
+    pub fn done(&mut self, update: &Update) {
+        self.send(update, true);
+        self.done = true;
+    }
+}
+
+impl Drop for StreamReporter {
+    fn drop(&mut self) {
+        if !self.done {
+            self.send(&self.last, true);
+        }
+    }
+}
+
+/// Direction of the data on a stream
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    #[default]
+    Upload,
+    Download,
+    /// Sum of the upload and download streams
+    Bidir,
+}
+
+impl Direction {
+    fn new(config: &TestConfig, streamid: u32) -> Self {
+        match config.is_download(streamid) {
+            true => Direction::Download,
+            false => Direction::Upload,
+        }
+    }
+
+    fn tag(&self) -> &'static str {
+        match self {
+            Direction::Upload => "UP",
+            Direction::Download => "DN",
+            Direction::Bidir => "BI",
+        }
+    }
+}
+
+/// TCP_INFO statistics of the sending side of a stream at the end of an interval
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct TcpStats {
+    /// Segments retransmitted during the interval
+    pub retransmits: u64,
+    /// Congestion window in bytes
+    pub cwnd: u64,
+    /// Smoothed round-trip time
+    pub rtt_ms: f64,
+    /// Round-trip time mean deviation
+    pub rttvar_ms: f64,
+    pub pacing_rate: u64,
+    pub delivery_rate: u64,
+}
+
+impl TcpStats {
+    fn new(tcp: &TcpInfo) -> Self {
+        Self {
+            retransmits: tcp.retransmits,
+            cwnd: tcp.cwnd,
+            rtt_ms: tcp.rtt.as_secs_f64() * 1000.0,
+            rttvar_ms: tcp.rttvar.as_secs_f64() * 1000.0,
+            pacing_rate: tcp.pacing_rate,
+            delivery_rate: tcp.delivery_rate,
+        }
+    }
+
+    /// Return the sum of the specified streams statistics, with the average round-trip time
+    fn sum<'a, I: Iterator<Item = &'a TcpStats>>(streams: I) -> Option<Self> {
+        let mut sum = Self::default();
+        let mut count = 0;
+        for stream in streams {
+            count += 1;
+            sum.retransmits += stream.retransmits;
+            sum.cwnd += stream.cwnd;
+            sum.rtt_ms += stream.rtt_ms;
+            sum.rttvar_ms += stream.rttvar_ms;
+            sum.pacing_rate += stream.pacing_rate;
+            sum.delivery_rate += stream.delivery_rate;
+        }
+        if count == 0 {
+            return None;
+        }
+        sum.rtt_ms /= count as f64;
+        sum.rttvar_ms /= count as f64;
+        Some(sum)
+    }
+}
+
+/// Statistics of a stream, or of the sum of all streams, during an interval
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct StreamStats {
+    /// Stream ID or None for the sum of all streams
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub streamid: Option<u32>,
+    pub direction: Direction,
+    pub start: f64,
+    pub end: f64,
+    pub bytes: u64,
+    pub bits_per_second: u64,
+    pub packets: u64,
+    pub lost: u64,
+    pub lost_percent: f64,
+    pub out_of_order: u64,
+    pub duplicates: u64,
+    /// UDP interarrival jitter, None if it is not measured: the datagrams
+    /// sent with GSO share their timestamp and the datagrams coalesced
+    /// by GRO share their arrival time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jitter_ms: Option<f64>,
+    /// CPU utilization of the stream, in percent of one core
+    pub cpu_percent: f64,
+    /// TCP_INFO statistics reported by the sending side of TCP streams
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tcp: Option<TcpStats>,
+    /// UDP datagrams sent with GSO and received with GRO, false if
+    /// a side fell back to sendmmsg() or recvmmsg()
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub offload: bool,
+    /// Interval of the omitted period, not accounted in the results
+    pub omitted: bool,
+}
+
+impl StreamStats {
+    fn new(config: &TestConfig, streamid: u32, start: Duration, update: &Update) -> Self {
+        Self {
+            streamid: Some(streamid),
+            direction: Direction::new(config, streamid),
+            start: start.as_secs_f64(),
+            end: (start + update.elapsed).as_secs_f64(),
+            bytes: update.bytes,
+            bits_per_second: update.get_througtput(),
+            packets: update.pktcount,
+            lost: update.pktlost,
+            lost_percent: update.get_loss_percent(),
+            out_of_order: update.pktoutoforder,
+            duplicates: update.pktduplicate,
+            jitter_ms: match config.udp && !config.gso {
+                true => Some(update.jitter.as_secs_f64() * 1000.0),
+                false => None,
+            },
+            cpu_percent: cpu::percent(update.cpu, update.elapsed),
+            tcp: update.tcp.as_ref().map(TcpStats::new),
+            offload: update.offload,
+            omitted: update.omitted,
+        }
+    }
+
+    /// Return the sum of the specified streams statistics
+    fn sum<'a, I: Iterator<Item = &'a StreamStats>>(streams: I, direction: Direction) -> Self {
+        let mut sum = Self {
+            direction,
+            start: f64::MAX,
+            offload: true,
+            ..Default::default()
+        };
+        let mut count = 0;
+        let mut tcp = vec!();
+        let mut jitters = vec!();
+        for stream in streams {
+            count += 1;
+            sum.start = sum.start.min(stream.start);
+            sum.end = sum.end.max(stream.end);
+            sum.bytes += stream.bytes;
+            sum.bits_per_second += stream.bits_per_second;
+            sum.packets += stream.packets;
+            sum.lost += stream.lost;
+            sum.out_of_order += stream.out_of_order;
+            sum.duplicates += stream.duplicates;
+            jitters.extend(stream.jitter_ms);
+            sum.cpu_percent += stream.cpu_percent;
+            sum.offload &= stream.offload;
+            sum.omitted |= stream.omitted;
+            tcp.extend(stream.tcp);
+        }
+        sum.tcp = TcpStats::sum(tcp.iter());
+        if count == 0 {
+            sum.start = 0.0;
+            sum.offload = false;
+        }
+        if !jitters.is_empty() {
+            sum.jitter_ms = Some(jitters.iter().sum::<f64>() / jitters.len() as f64);
+        }
+        if sum.packets + sum.lost > 0 {
+            sum.lost_percent = 100.0 * sum.lost as f64 / (sum.packets + sum.lost) as f64;
+        }
+        sum
+    }
+}
+
+/// Statistics of all the streams during an interval
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IntervalStats {
+    pub streams: Vec<StreamStats>,
+    pub sum: StreamStats,
+    /// Sum of the upload streams in bidirectional mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sum_upload: Option<StreamStats>,
+    /// Sum of the download streams in bidirectional mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sum_download: Option<StreamStats>,
+}
+
+impl IntervalStats {
+    fn new(config: &TestConfig, streams: Vec<StreamStats>) -> Self {
+        let direction_sum = |direction| {
+            StreamStats::sum(streams.iter().filter(|stats| stats.direction == direction), direction)
+        };
+        match config.bidir {
+            true => Self {
+                sum: StreamStats::sum(streams.iter(), Direction::Bidir),
+                sum_upload: Some(direction_sum(Direction::Upload)),
+                sum_download: Some(direction_sum(Direction::Download)),
+                streams,
+            },
+            false => Self {
+                sum: StreamStats::sum(streams.iter(), Direction::new(config, 0)),
+                sum_upload: None,
+                sum_download: None,
+                streams,
+            },
+        }
+    }
+
+    /// Return the sums to report: one per direction
+    pub fn sums(&self) -> Vec<&StreamStats> {
+        match (&self.sum_upload, &self.sum_download) {
+            (Some(upload), Some(download)) => vec!(upload, download),
+            _ => vec!(&self.sum),
+        }
+    }
+}
+
+/// Results of a full test run
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TestResults {
+    /// Test configuration
+    pub config: TestConfig,
+    /// Receiver side statistics for each interval
+    pub intervals: Vec<IntervalStats>,
+    /// Receiver side statistics for the whole test
+    pub end: IntervalStats,
+    /// Server side statistics for the whole test
+    pub server: Vec<StreamStats>,
+    /// CPU utilization during the test
+    pub cpu: CpuUsage,
+}
+
+/// CPU utilization during a test, in percent of one core
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct CpuUsage {
+    /// Client process
+    pub local_percent: f64,
+    /// Server data streams
+    pub remote_percent: f64,
+}
+
+/// How the test results are reported while the test is running
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Output {
+    /// Print the intervals and the summary on the standard output
+    pub print: bool,
+    /// Units used to print the results, auto-scaled if not set
+    pub format: Option<Format>,
+    /// Draw the intervals in dataviewer
+    pub view: bool,
+}
+
+/// Statistics of a stream during an interval not yet printed
+#[derive(Debug, Default)]
+struct PendingStream {
+    /// Receiver side statistics
+    stats: Option<StreamStats>,
+    /// TCP_INFO statistics of the sending side
+    tcp: Option<TcpStats>,
+}
+
+/// Collect the reports of all streams and print one table per interval
+/// with a row per stream and a SUM row, followed by a final summary.
+///
+/// The rows hold the receiver side statistics, completed with the TCP_INFO
+/// statistics reported by the sending side of TCP streams.
+pub struct Aggregator {
+    config: TestConfig,
+    output: Output,
+    /// The server reports the TCP_INFO statistics of its sending streams
+    remote_tcp_info: bool,
+    /// Final results of the streams which are done
+    results: BTreeMap<u32, Update>,
+    /// Final results of the sending side of the streams which are done
+    senders: BTreeMap<u32, Update>,
+    /// Intervals not yet printed, indexed by their start
+    /// after the intervals of the omitted period
+    pending: BTreeMap<(bool, u64), BTreeMap<u32, PendingStream>>,
+    /// Last interval printed
+    completed: Option<(bool, u64)>,
+    intervals: Vec<IntervalStats>,
+    viewer: Option<Viewer>,
+}
+
+impl Aggregator {
+    pub fn new(config: &TestConfig, output: &Output, remote_tcp_info: bool) -> Result<Self> {
+        let viewer = match output.view {
+            true => Some(Viewer::new(config.udp)?),
+            false => None,
+        };
+
+        Ok(Self {
+            config: config.clone(),
+            output: output.clone(),
+            remote_tcp_info,
+            results: BTreeMap::new(),
+            senders: BTreeMap::new(),
+            pending: BTreeMap::new(),
+            completed: None,
+            intervals: Vec::new(),
+            viewer,
+        })
+    }
+
+    /// Aggregate the stream reports until all reporters are gone
+    pub fn run(mut self, reports: Receiver<StreamReport>) -> TestResults {
+        for report in reports {
+            self.push(report);
+        }
+        self.flush((true, u64::MAX));
+
+        let streams = self.results.iter()
+            .map(|(streamid, update)| {
+                let sender = self.senders.get(streamid);
+                StreamStats {
+                    tcp: sender.and_then(|sender| sender.tcp.as_ref()).map(TcpStats::new),
+                    // Both sides of an UDP stream offload the datagrams
+                    offload: update.offload && sender.is_none_or(|sender| sender.offload),
+                    ..StreamStats::new(&self.config, *streamid, Duration::ZERO, update)
+                }
+            })
+            .collect();
+        let end = IntervalStats::new(&self.config, streams);
+        if self.output.print {
+            println!("- - - - - - - - - - - - - - - - - - - - - - - - -");
+            self.print(&end);
+        }
+
+        TestResults {
+            config: self.config,
+            intervals: self.intervals,
+            end,
+            server: Vec::new(),
+            cpu: CpuUsage::default(),
+        }
+    }
+
+    fn push(&mut self, report: StreamReport) {
+        if report.done {
+            match report.sender {
+                true => {self.senders.insert(report.streamid, report.update);},
+                false => {self.results.insert(report.streamid, report.update);},
+            }
+            self.flush_completed();
+            return;
+        }
+
+        let delta = match &report.update.interval {
+            Some(delta) => delta,
+            None => {return;},
+        };
+        let start = report.update.elapsed.saturating_sub(delta.elapsed);
+
+        // The intervals are reported late rather than early:
+        // the streams intervals are matched on their start.
+        let interval = self.config.get_interval().as_nanos();
+        let measured = !report.update.omitted;
+        let index = (start.as_nanos() / interval) as u64;
+        if self.completed.is_some_and(|completed| (measured, index) <= completed) {
+            // Too late, the interval was already printed
+            return;
+        }
+        let pending = self.pending.entry((measured, index)).or_default()
+            .entry(report.streamid).or_default();
+        match report.sender {
+            true => pending.tcp = delta.tcp.as_ref().map(TcpStats::new),
+            false => pending.stats = Some(StreamStats::new(&self.config, report.streamid, start, delta)),
+        }
+
+        // Do not wait more than a second for a late stream
+        let slack = std::cmp::max(1, Duration::from_secs(1).as_nanos() / interval) as u64;
+        self.flush((measured, index.saturating_sub(slack)));
+        self.flush_completed();
+    }
+
+    /// Return true if the sending side of the stream reports its TCP_INFO statistics
+    fn has_tcp_info(&self, streamid: u32) -> bool {
+        !self.config.udp && (self.remote_tcp_info || !self.config.is_download(streamid))
+    }
+
+    /// Complete the intervals every running stream has reported
+    fn flush_completed(&mut self) {
+        while let Some((_, streams)) = self.pending.first_key_value() {
+            let reported = |streamid| {
+                let pending = streams.get(&streamid);
+                let received = pending.is_some_and(|pending| pending.stats.is_some()) || self.results.contains_key(&streamid);
+                let sent = !self.has_tcp_info(streamid) || pending.is_some_and(|pending| pending.tcp.is_some())
+                    || self.senders.contains_key(&streamid);
+                received && sent
+            };
+            if !(0..self.config.get_streams()).all(reported) {
+                break;
+            }
+            if let Some((index, streams)) = self.pending.pop_first() {
+                self.complete(index, streams);
+            }
+        }
+    }
+
+    /// Complete all the intervals starting before the specified interval
+    fn flush(&mut self, index: (bool, u64)) {
+        while let Some(entry) = self.pending.first_entry() {
+            if *entry.key() >= index {
+                break;
+            }
+            let (index, streams) = entry.remove_entry();
+            self.complete(index, streams);
+        }
+    }
+
+    fn complete(&mut self, index: (bool, u64), streams: BTreeMap<u32, PendingStream>) {
+        self.completed = Some(index);
+        let streams: Vec<StreamStats> = streams.into_values()
+            .filter_map(|pending| pending.stats.map(|stats| StreamStats { tcp: pending.tcp, ..stats }))
+            .collect();
+        // Only the sending side reported the interval
+        if streams.is_empty() {
+            return;
+        }
+        let interval = IntervalStats::new(&self.config, streams);
+        if self.output.print {
+            self.print(&interval);
+            println!();
+        }
+        // The clock restarts after the omitted period: only draw the measured intervals
+        if let Some(viewer) = self.viewer.as_mut().filter(|_| !interval.sum.omitted) {
+            if let Err(e) = viewer.push(&interval) {
+                if self.output.print {
+                    println!("{:#}", e);
+                }
+            }
+        }
+        self.intervals.push(interval);
+    }
+
+    fn print_row(&self, stats: &StreamStats) {
+        let mut id = match stats.streamid {
+            Some(streamid) => format!("[{:>3}]", streamid),
+            None => "[SUM]".to_string(),
+        };
+        if self.config.bidir {
+            id += &format!("[{:<3}]", stats.direction.tag());
+        }
+        print!("{} {:>6.2}-{:<6.2} sec  {:>12}  {:>15}",
+            id, stats.start, stats.end,
+            units::format_bytes(stats.bytes, self.output.format),
+            units::format_rate(stats.bits_per_second, self.output.format));
+        if self.config.udp {
+            let jitter = match stats.jitter_ms {
+                Some(jitter_ms) => format!("{:.3} ms", jitter_ms),
+                None => "n/a".to_string(),
+            };
+            print!("  {:>8}/{:<8} ({:.3}%)  {}",
+                stats.lost, stats.packets + stats.lost, stats.lost_percent, jitter);
+        }
+        if let Some(tcp) = &stats.tcp {
+            print!("  {:>6}  {:>12}  {:>8.2} ms",
+                tcp.retransmits, units::format_bytes(tcp.cwnd, None), tcp.rtt_ms);
+        }
+        if stats.omitted {
+            print!("  (omitted)");
+        }
+        println!();
+    }
+
+    fn print(&self, interval: &IntervalStats) {
+        print!("[ ID]");
+        if self.config.bidir {
+            print!("[DIR]");
+        }
+        print!(" Interval            Transfer          Bitrate");
+        if self.config.udp {
+            print!("       Lost/Total Datagrams  Jitter");
+        }
+        if interval.sum.tcp.is_some() {
+            print!("    Retr          Cwnd          RTT");
+        }
+        println!();
+        for stats in &interval.streams {
+            self.print_row(stats);
+        }
+        if self.config.parallel > 1 {
+            for sum in interval.sums() {
+                self.print_row(sum);
+            }
+        }
+    }
+}
+
+/// Return the server side statistics of a stream for the whole test
+pub fn server_stats(config: &TestConfig, report: &StreamReport) -> StreamStats {
+    StreamStats::new(config, report.streamid, Duration::ZERO, &report.update)
+}
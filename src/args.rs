@@ -1,10 +1,12 @@
 /// speednet command line arguments
 ///
 use clap::Parser;
-use serde::Deserialize;
-use serde::Serialize;
+use crate::config::TestConfig;
+use crate::units::{self, Format};
+use std::path::PathBuf;
+use std::time::Duration;
 
-#[derive(Parser, Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+#[derive(Parser, Debug, Clone, PartialEq)]
 pub struct ArgsClient {
     /// speednet server hostname
     pub hostname: String,
@@ -13,6 +15,14 @@ pub struct ArgsClient {
     #[arg(short, long, default_value_t=4000)]
     pub port: u16,
 
+    /// Only use IPv4 addresses of the server
+    #[arg(short='4', long, conflicts_with="ipv6")]
+    pub ipv4: bool,
+
+    /// Only use IPv6 addresses of the server
+    #[arg(short='6', long)]
+    pub ipv6: bool,
+
     /// Use UDP instead of TCP
     #[arg(short, long)]
     pub udp: bool,
@@ -21,47 +31,141 @@ pub struct ArgsClient {
     #[arg(short='R', long)]
     pub revert: bool,
 
+    /// Upload and Download at the same time
+    #[arg(long, conflicts_with="revert")]
+    pub bidir: bool,
+
     /// Set DSCP in packet IP Header
-    #[arg(short, long)]
-    pub dscp: Option<i32>,
+    #[arg(short, long, value_parser=clap::value_parser!(u8).range(0..=63))]
+    pub dscp: Option<u8>,
 
     /// Set packet MARK
     #[arg(short, long)]
-    pub mark: Option<i32>,
+    pub mark: Option<u32>,
 
-    /// Bind the specified IP Address
+    /// Bind the specified source IP Address
     #[arg(short='B', long)]
     pub bind: Option<String>,
 
-    /// Set a target bandwidth
-    #[arg(short, long)]
+    /// Set a target bandwidth in bits per second (e.g. 10M, 2.5G)
+    #[arg(short, long, value_parser=units::parse_size)]
     bandwidth: Option<u64>,
 
+    /// Set the rate limiter burst size in bytes (e.g. 64K, 1Mi)
+    /// [default: 10ms at the target bandwidth]
+    #[arg(long, value_parser=units::parse_size)]
+    burst: Option<u64>,
+
     /// Set the number of open connections in parallel
     #[arg(short='P', long, default_value_t=1)]
     pub parallel: u32,
 
-    /// Set the buffer len to use to send/recv packets
-    #[arg(short, long, default_value_t=100000)]
-    len: u64,
+    /// Set the buffer len to use to send/recv packets (e.g. 64K, 1Ki)
+    /// [default: 100000 for TCP, 1400 for UDP]
+    #[arg(short, long, value_parser=units::parse_size)]
+    len: Option<u64>,
+
+    /// Send the TCP data with sendfile() to avoid copying them from user space
+    #[arg(short='Z', long, conflicts_with="udp")]
+    pub zerocopy: bool,
+
+    /// Send the UDP datagrams with GSO and receive them with GRO, without measuring the jitter
+    #[arg(long, requires="udp")]
+    pub gso: bool,
+
+    /// Set the TCP congestion control algorithm of the sending side (e.g. cubic, bbr)
+    #[arg(short='C', long, conflicts_with="udp")]
+    pub congestion: Option<String>,
 
-    /// The test duration time
-    #[arg(short, long, default_value_t=10)]
-    pub time: u64,
+    /// The test duration time [default: 10, or the server maximum with --bytes or --packets]
+    #[arg(short, long)]
+    pub time: Option<u64>,
+
+    /// Stop each stream after sending N bytes (e.g. 1G, 100Mi)
+    #[arg(short='n', long, value_parser=units::parse_size)]
+    pub bytes: Option<u64>,
+
+    /// Stop each stream after sending N packets (TCP writes or UDP datagrams)
+    #[arg(short='k', long, value_parser=units::parse_size)]
+    pub packets: Option<u64>,
+
+    /// Omit the first N seconds of the test from the results (e.g. TCP slow start)
+    #[arg(short='O', long, default_value_t=0)]
+    pub omit: u64,
+
+    /// Seconds between the statistics reports (e.g. 0.1, 5, 500ms)
+    #[arg(short, long, default_value="1", value_parser=units::parse_duration)]
+    pub interval: Duration,
 
     /// Draw speednet results in dataviewer
     #[arg(short, long)]
     pub view: bool,
+
+    /// Force the units used to print the results [default: auto-scaled]
+    #[arg(short, long)]
+    pub format: Option<Format>,
+
+    /// Output the test results in JSON format
+    #[arg(short='J', long)]
+    pub json: bool,
 }
 
-#[derive(Parser, Debug, Clone, PartialEq, Default)]
+#[derive(Parser, Debug, Clone, PartialEq)]
 pub struct ArgsServer {
-    /// Bind the specified IP Address
+    /// Bind the specified IP Address or hostname [default: all addresses]
     pub bind: Option<String>,
 
     /// speednet server control port
     #[arg(short, long, default_value_t=4000)]
     pub port: u16,
+
+    /// Only listen on IPv4 [default: IPv4 and IPv6]
+    #[arg(short='4', long, conflicts_with="ipv6")]
+    pub ipv4: bool,
+
+    /// Only listen on IPv6 [default: IPv4 and IPv6]
+    #[arg(short='6', long)]
+    pub ipv6: bool,
+
+    /// Maximum number of tests running at the same time
+    #[arg(long, default_value_t=10)]
+    pub max_tests: usize,
+
+    /// Maximum number of data streams per test
+    #[arg(long, default_value_t=128)]
+    pub max_streams: u32,
+
+    /// Maximum test duration in seconds
+    #[arg(long, default_value_t=3600)]
+    pub max_time: u64,
+
+    /// Maximum bandwidth per client IP address in bits per second,
+    /// shared by its running tests (e.g. 1G) [default: unlimited]
+    #[arg(long, value_parser=units::parse_size)]
+    pub max_bandwidth: Option<u64>,
+
+    /// Handle a single test and exit, with an error status if the test did not complete
+    #[arg(short='1', long)]
+    pub one_off: bool,
+
+    /// Run the server in background as a daemon
+    #[arg(short='D', long)]
+    pub daemon: bool,
+
+    /// Write the server process ID in the specified file
+    #[arg(short='I', long)]
+    pub pidfile: Option<PathBuf>,
+
+    /// Write the server logs in the specified file
+    #[arg(long)]
+    pub logfile: Option<PathBuf>,
+}
+
+impl Default for ArgsServer {
+    /** Return the server arguments with their command line default values */
+    fn default() -> Self {
+        Self::parse_from(["server"])
+    }
 }
 
 #[derive(clap::Subcommand, Debug)]
@@ -81,26 +185,30 @@ pub struct Args {
 }
 
 
-impl ArgsClient {
-    /** Return the test bandwidth */
-    pub fn get_bandwidth(&self) -> u64 {
-        self.bandwidth.unwrap_or(0)
-    }
-
-    /** Return the socket buffer len */
-    pub fn get_bufferlen(&self) -> u64 {
-        let len = std::cmp::min(self.len, 10*1000*1000);
-        std::cmp::max(len, 10)
-    }
-
-    /** Return the number of total packets to send for this test */
-    pub fn get_totalpackets(&self) -> u64 {
-        let bandwidth = match self.bandwidth {
-            Some(bandwidth) => bandwidth,
-            None => {return 0;},
-        };
-        let bufferlen = self.get_bufferlen();
-
-        self.time * bandwidth / (8 * bufferlen)
+impl From<&ArgsClient> for TestConfig {
+    fn from(args: &ArgsClient) -> Self {
+        Self {
+            udp: args.udp,
+            revert: args.revert,
+            bidir: args.bidir,
+            dscp: args.dscp,
+            mark: args.mark,
+            bandwidth: args.bandwidth,
+            burst: args.burst,
+            parallel: args.parallel,
+            len: args.len,
+            zerocopy: args.zerocopy,
+            gso: args.gso,
+            congestion: args.congestion.clone(),
+            // Without a duration, a test bounded by a count runs until it is reached
+            time: args.time.unwrap_or(match args.bytes.is_some() || args.packets.is_some() {
+                true => 0,
+                false => 10,
+            }),
+            bytes: args.bytes,
+            packets: args.packets,
+            omit: args.omit,
+            interval_ms: args.interval.as_millis() as u64,
+        }
     }
 }
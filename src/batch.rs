@@ -0,0 +1,214 @@
+/// Batched UDP I/O with sendmmsg/recvmmsg and UDP GSO/GRO
+///
+/// A batch of datagrams is sent or received with a single system call.
+/// With GSO, the datagrams are passed to the kernel as a single
+/// super-datagram segmented on output, and with GRO the received datagrams
+/// of a flow may be coalesced: they are split back before being accounted.
+use std::io;
+use std::net::UdpSocket;
+use std::os::fd::AsRawFd;
+
+/// Maximum number of datagrams sent or received per system call.
+/// Larger batches overflow the peer socket buffer before it is scheduled.
+const MAX_BATCH: usize = 16;
+
+/// Maximum number of segments of a GSO super-datagram (UDP_MAX_SEGMENTS)
+const MAX_GSO_SEGMENTS: usize = 64;
+
+/// Maximum number of GRO super-datagrams received per system call
+const MAX_GRO_BATCH: usize = 8;
+
+/// Maximum size of an UDP payload, also bounding the GSO and GRO super-datagrams
+const MAX_UDP_PAYLOAD: usize = 65507;
+
+/// Size of the GRO super-datagrams receive buffers
+const GRO_BUFFER_LEN: usize = 65536;
+
+/// Size of the control message buffer of each received message
+const CONTROL_LEN: usize = 64;
+
+fn set_udp_option(socket: &UdpSocket, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let rc = unsafe {
+        libc::setsockopt(socket.as_raw_fd(), libc::SOL_UDP, name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Send datagrams by batches, as GSO super-datagrams or with sendmmsg()
+pub struct BatchSender {
+    gso: bool,
+    iovecs: Vec<libc::iovec>,
+    msgs: Vec<libc::mmsghdr>,
+}
+
+impl BatchSender {
+    /// Create a sender of datagrams of len bytes, using GSO if requested
+    /// and supported by the kernel, or falling back to sendmmsg().
+    pub fn new(socket: &UdpSocket, len: usize, gso: bool) -> Self {
+        let gso = gso && set_udp_option(socket, libc::UDP_SEGMENT, len as libc::c_int).is_ok();
+        Self {
+            gso,
+            iovecs: Vec::with_capacity(MAX_BATCH),
+            msgs: Vec::with_capacity(MAX_BATCH),
+        }
+    }
+
+    /// Return true if the datagrams are sent with GSO
+    pub fn is_gso(&self) -> bool {
+        self.gso
+    }
+
+    /// Return the maximum number of datagrams of len bytes sent at once
+    pub fn max_batch(&self, len: usize) -> usize {
+        match self.gso {
+            true => (MAX_UDP_PAYLOAD / len).clamp(1, MAX_GSO_SEGMENTS),
+            false => MAX_BATCH,
+        }
+    }
+
+    /// Send the datagrams of len bytes stored contiguously in buffer.
+    /// Return the number of datagrams sent.
+    pub fn send(&mut self, socket: &UdpSocket, buffer: &[u8], len: usize) -> io::Result<usize> {
+        if self.gso {
+            match socket.send(buffer) {
+                Ok(_) => {return Ok(buffer.len().div_ceil(len));},
+                // The output device can not segment the datagrams:
+                // the same datagrams are sent with sendmmsg()
+                Err(e) if e.raw_os_error() == Some(libc::EIO) || e.raw_os_error() == Some(libc::EINVAL) => {
+                    set_udp_option(socket, libc::UDP_SEGMENT, 0)?;
+                    self.gso = false;
+                },
+                Err(e) => {return Err(e);},
+            }
+        }
+        self.sendmmsg(socket, buffer, len)
+    }
+
+    fn sendmmsg(&mut self, socket: &UdpSocket, buffer: &[u8], len: usize) -> io::Result<usize> {
+        self.iovecs.clear();
+        self.iovecs.extend(buffer.chunks(len).take(MAX_BATCH).map(|datagram| libc::iovec {
+            iov_base: datagram.as_ptr() as *mut libc::c_void,
+            iov_len: datagram.len(),
+        }));
+        self.msgs.clear();
+        self.msgs.extend(self.iovecs.iter_mut().map(|iovec| {
+            let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            msg.msg_hdr.msg_iov = iovec;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        }));
+
+        let sent = unsafe { libc::sendmmsg(socket.as_raw_fd(), self.msgs.as_mut_ptr(), self.msgs.len() as libc::c_uint, 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+}
+
+/// Receive datagrams by batches with recvmmsg(), possibly coalesced by GRO
+pub struct BatchReceiver {
+    gro: bool,
+    /// Size of the buffer of each message
+    slot: usize,
+    buffer: Vec<u8>,
+    /// Control messages buffers, aligned for cmsghdr
+    control: Vec<u64>,
+    /// Length and GRO segment size of the messages of the last batch
+    received: Vec<(usize, usize)>,
+    iovecs: Vec<libc::iovec>,
+    msgs: Vec<libc::mmsghdr>,
+}
+
+impl BatchReceiver {
+    /// Create a receiver of datagrams of at most len bytes, using GRO
+    /// if requested and supported by the kernel, or falling back to recvmmsg().
+    pub fn new(socket: &UdpSocket, len: usize, gro: bool) -> Self {
+        let gro = gro && set_udp_option(socket, libc::UDP_GRO, 1).is_ok();
+        let (batch, slot) = match gro {
+            true => (MAX_GRO_BATCH, GRO_BUFFER_LEN),
+            false => (MAX_BATCH, len),
+        };
+        Self {
+            gro,
+            slot,
+            buffer: vec!(0; batch * slot),
+            control: vec!(0; batch * CONTROL_LEN / 8),
+            received: Vec::with_capacity(batch),
+            iovecs: Vec::with_capacity(batch),
+            msgs: Vec::with_capacity(batch),
+        }
+    }
+
+    /// Return true if the datagrams are received with GRO
+    pub fn is_gro(&self) -> bool {
+        self.gro
+    }
+
+    /// Receive a batch of messages, only waiting for the first one.
+    ///
+    /// Return the number of messages received, or 0 if the socket
+    /// was shut down or an empty datagram was received, as recv().
+    pub fn recv(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        self.iovecs.clear();
+        self.iovecs.extend(self.buffer.chunks_mut(self.slot).map(|slot| libc::iovec {
+            iov_base: slot.as_mut_ptr() as *mut libc::c_void,
+            iov_len: slot.len(),
+        }));
+        self.msgs.clear();
+        for (iovec, control) in self.iovecs.iter_mut().zip(self.control.chunks_mut(CONTROL_LEN / 8)) {
+            let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            msg.msg_hdr.msg_iov = iovec;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg.msg_hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_hdr.msg_controllen = CONTROL_LEN as _;
+            self.msgs.push(msg);
+        }
+
+        // MSG_WAITFORONE: the read timeout only applies to the first message
+        let count = unsafe {
+            libc::recvmmsg(socket.as_raw_fd(), self.msgs.as_mut_ptr(), self.msgs.len() as libc::c_uint,
+                libc::MSG_WAITFORONE, std::ptr::null_mut())
+        };
+        if count < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.received.clear();
+        for msg in &self.msgs[..count as usize] {
+            let len = msg.msg_len as usize;
+            if len == 0 {
+                return Ok(0);
+            }
+            self.received.push((len, gro_segment(&msg.msg_hdr).unwrap_or(len)))
+        }
+        Ok(self.received.len())
+    }
+
+    /// Return the datagrams of the last batch, split from the GRO super-datagrams
+    pub fn datagrams(&self) -> impl Iterator<Item = &[u8]> {
+        self.received.iter().enumerate().flat_map(|(i, (len, segment))| {
+            self.buffer[i * self.slot..i * self.slot + len].chunks(*segment)
+        })
+    }
+}
+
+/// Return the size of the datagrams coalesced by GRO in the received message
+fn gro_segment(msg: &libc::msghdr) -> Option<usize> {
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == libc::UDP_GRO {
+                let segment = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                return Some(segment as usize).filter(|segment| *segment > 0);
+            }
+            cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+        }
+    }
+    None
+}
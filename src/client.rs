@@ -1,36 +1,75 @@
 use eyre::{eyre, Result, WrapErr};
-use std::net::{TcpStream, UdpSocket, SocketAddr, IpAddr};
+use std::net::{TcpStream, UdpSocket, SocketAddr};
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver};
 use crate::{
+    aggregator::{self, Aggregator, CpuUsage, Output, StreamReporter, TestResults},
     args::ArgsClient,
-    message::{Message, MessageIO},
+    config::TestConfig,
+    cpu,
+    message::{self, Message, MessageIO, StreamReport},
     pktgenerator,
+    socket::{self, Family},
+    units,
 };
 
+/// speednet client running a speedtest against a speednet server
 pub struct Client {
-    args: ArgsClient,
+    config: TestConfig,
+    bind: Option<String>,
+    output: Output,
     control_addr: SocketAddr,
     control_stream: TcpStream,
+    /// Clone of the control connection the streams send their messages on
+    control: Arc<Mutex<TcpStream>>,
 }
 
 struct Stream {
-    args: ArgsClient,
-    testid: u32,
+    config: TestConfig,
+    bind: Option<String>,
+    testid: u64,
     streamid: u32,
     control_addr: SocketAddr,
+    /// Control connection shared by the streams, to send the report
+    /// ending the omitted period of the TCP upload streams
+    control: Arc<Mutex<TcpStream>>,
+    /// Notify the client that the stream is initialized, or failed
+    ready: Sender<Result<()>>,
+    start: Receiver<()>,
+    reports: Sender<StreamReport>,
+    /// Reports of the server sending a download stream
+    sender: Receiver<StreamReport>,
 }
 
 impl Stream {
-    pub fn new(client: &Client, testid: u32, streamid: u32) -> Self {
+    pub fn new(client: &Client, testid: u64, streamid: u32, ready: Sender<Result<()>>, start: Receiver<()>, reports: Sender<StreamReport>, sender: Receiver<StreamReport>) -> Self {
         Self {
-            args: client.args.clone(),
+            config: client.config.clone(),
+            bind: client.bind.clone(),
             testid,
             streamid,
-            control_addr: client.control_addr.clone(),
+            control_addr: client.control_addr,
+            control: client.control.clone(),
+            ready,
+            start,
+            reports,
+            sender,
         }
     }
 
+    /// Notify the client that the stream is initialized
+    /// and wait for all the streams to be ready.
+    fn wait_start(&self) -> Result<()> {
+        self.ready.send(Ok(()))
+            .wrap_err("Client is gone")?;
+        self.start.recv()
+            .wrap_err("Test aborted")?;
+        Ok(())
+    }
+
     pub fn run(&self) -> Result<()> {
-        if self.args.udp {
+        if self.config.udp {
             self.run_udp()?;
         }
         else {
@@ -40,30 +79,89 @@ impl Stream {
     }
 
     pub fn run_udp(&self) -> Result<()> {
-        let bindaddr = match self.control_addr.is_ipv4() {
-            true  => "0.0.0.0:0",
-            false => "[::0]:0",
-        };
-        let mut s = UdpSocket::bind(bindaddr)
-            .wrap_err("Failed to bind addr")?;
+        let mut socket = socket::udp_connect(self.control_addr, self.bind.as_deref(), &self.config)?;
 
-        let start_udp = Message::ClientStreamHello(self.testid, self.streamid);
-        s.sendmsg(&start_udp)
+        self.udp_stream_hello(&mut socket)
             .wrap_err("Client failed to start UDP")?;
+        self.wait_start()?;
 
+        if self.config.is_download(self.streamid) {
+            self.run_udp_download(socket)?;
+        }
+        else {
+            self.run_udp_upload(socket)?;
+        }
 
         Ok(())
     }
 
+    /// Initialize the UDP data stream with the server.
+    ///
+    /// The ClientStreamHello is resent until the server acknowledge it
+    /// in order to handle packet loss.
+    fn udp_stream_hello(&self, socket: &mut UdpSocket) -> Result<()> {
+        let start_udp = Message::ClientStreamHello(self.testid, self.streamid);
+        let mut buff = vec!(0; 65536);
+
+        socket.set_read_timeout(Some(Duration::from_millis(500)))
+            .wrap_err("Failed to set read timeout")?;
+        for _ in 0..10 {
+            socket.sendmsg(&start_udp)?;
+
+            let len = match socket.recv(&mut buff) {
+                Ok(len) => len,
+                Err(_) => {continue;},
+            };
+            match Message::decode(&buff[..len])? {
+                Message::ServerStreamHello => {},
+                Message::ServerReject(reason) => {return Err(eyre!("Server rejected the stream: {}", reason));},
+                msg => {return Err(eyre!("Expected ServerStreamHello message iso {:?}", msg));},
+            }
+            socket.set_read_timeout(None)
+                .wrap_err("Failed to clear read timeout")?;
+            return Ok(());
+        }
+
+        Err(eyre!("Server did not acknowledge UDP stream"))
+    }
+
+    pub fn run_udp_upload(&self, socket: UdpSocket) -> Result<()> {
+        // Upload statistics are reported by the server,
+        // completed with the GSO status of the sender
+        let mut reporter = StreamReporter::new(self.reports.clone(), self.streamid, true);
+        let result = pktgenerator::udp_send(&self.config, self.streamid, socket, |_| {})?;
+        // The server stops receiving once the datagrams in flight arrived
+        self.send_report(&result, true)?;
+        reporter.done(&result);
+        Ok(())
+    }
+
+    pub fn run_udp_download(&self, socket: UdpSocket) -> Result<()> {
+        let mut reporter = StreamReporter::new(self.reports.clone(), self.streamid, false);
+        let result = pktgenerator::udp_recv(&self.config, 0, &self.sender, self.streamid, socket, |update| {
+            reporter.update(update);
+        })?;
+        reporter.done(&result);
+        Ok(())
+    }
+
     pub fn run_tcp(&self) -> Result<()> {
-        let mut stream = TcpStream::connect(self.control_addr)
-            .wrap_err("Failed to connect to server")?;
+        let mut stream = socket::tcp_connect(self.control_addr, self.bind.as_deref(), Some(&self.config))?;
 
         let start_stream = Message::ClientStreamHello(self.testid, self.streamid);
         stream.sendmsg(&start_stream)
             .wrap_err("Client failed to start stream")?;
 
-        if self.args.revert {
+        let msg = stream.recvmsg()
+            .wrap_err("Failed to read server stream hello message")?;
+        match msg {
+            Message::ServerStreamHello => {},
+            Message::ServerReject(reason) => {return Err(eyre!("Server rejected the stream: {}", reason));},
+            _ => {return Err(eyre!("Expected ServerStreamHello message iso {:?}", msg));},
+        }
+        self.wait_start()?;
+
+        if self.config.is_download(self.streamid) {
             self.run_tcp_download(stream)?;
         }
         else {
@@ -74,64 +172,119 @@ impl Stream {
     }
 
     pub fn run_tcp_upload(&self, stream: TcpStream) -> Result<()> {
-        println!("TCP Upload");
-        let result = pktgenerator::tcp_send(&self.args, stream, |update| {
-            println!("Throughput: {}", update.get_througtput());
-            println!("Elapsed: {}", update.elapsed.as_secs());
-            println!("pktsent: {}", update.pktcount);
-            println!("expected: {}", update.pktcount_expected);
-            println!("");
+        // Upload statistics are reported by the server,
+        // completed with the TCP_INFO statistics of the sender
+        let mut reporter = StreamReporter::new(self.reports.clone(), self.streamid, true);
+        let mut control_result = Ok(());
+        let result = pktgenerator::tcp_send(&self.config, stream, |update| {
+            // The server ends the omitted period after the same bytes count
+            if update.omit_end && control_result.is_ok() {
+                control_result = self.send_report(update, false);
+            }
+            reporter.update(update);
         })?;
-        println!("TCP Upload done");
-        println!("Elapsed: {}", result.elapsed.as_secs());
-        println!("pktsent: {}", result.pktcount);
+        control_result?;
+        reporter.done(&result);
         Ok(())
     }
 
+    /// Send a report of the upload stream to the server
+    fn send_report(&self, update: &pktgenerator::Update, done: bool) -> Result<()> {
+        let report = StreamReport {
+            streamid: self.streamid,
+            done,
+            sender: true,
+            update: update.clone(),
+        };
+        self.control.lock().unwrap().sendmsg(&Message::ClientTestUpdate(report))
+            .wrap_err("Failed to send client test update")
+    }
+
     pub fn run_tcp_download(&self, stream: TcpStream) -> Result<()> {
-        println!("TCP Download");
-        let result = pktgenerator::tcp_recv(&self.args, stream, |update| {
-            println!("Throughput: {}", update.get_througtput());
-            println!("Elapsed: {}", update.elapsed.as_secs());
-            println!("pktrecv: {}", update.pktcount);
-            println!("");
+        let mut reporter = StreamReporter::new(self.reports.clone(), self.streamid, false);
+        let result = pktgenerator::tcp_recv(&self.config, 0, &self.sender, stream, |update| {
+            reporter.update(update);
         })?;
-        println!("TCP Download done");
-        println!("Elapsed: {}", result.elapsed.as_secs());
-        println!("Pkt Recv: {}", result.pktcount);
+        reporter.done(&result);
         Ok(())
     }
 }
 
 impl Client {
+    /// Connect to the server specified on the command line.
+    ///
+    /// The results are printed while the test is running, unless JSON
+    /// output is requested.
     pub fn new(args: ArgsClient) -> Result<Self> {
-        let ip_addr = args.hostname.parse::<IpAddr>()
-            .wrap_err("Invalid hostname")?;
+        let config = TestConfig::from(&args);
+        config.validate()?;
 
-        let addr = SocketAddr::new(ip_addr, args.port);
-        println!("speednet client connect to {:?}", addr);
+        let output = Output {
+            print: !args.json,
+            format: args.format,
+            view: args.view,
+        };
 
-        let stream = TcpStream::connect(addr)
-            .wrap_err("Failed to connect to server")?;
+        // Try each address of the server in turn
+        let family = Family::new(args.ipv4, args.ipv6);
+        let mut addrs = socket::resolve(&args.hostname, args.port, family)?.into_iter().peekable();
+        while let Some(addr) = addrs.next() {
+            if output.print {
+                println!("speednet client connect to {:?}", addr);
+            }
+            match Self::connect_with(addr, config.clone(), args.bind.clone(), output.clone()) {
+                Ok(client) => {return Ok(client);},
+                Err(e) if addrs.peek().is_some() => {
+                    if output.print {
+                        eprintln!("{:#}", e);
+                    }
+                },
+                Err(e) => {return Err(e);},
+            }
+        }
+        Err(eyre!("No address to connect to {}", args.hostname))
+    }
+
+    /// Connect to the specified server to run a test with the specified
+    /// configuration. Nothing is printed: the results are only returned by run().
+    pub fn connect(addr: SocketAddr, config: TestConfig) -> Result<Self> {
+        config.validate()?;
+        Self::connect_with(addr, config, None, Output::default())
+    }
+
+    fn connect_with(addr: SocketAddr, config: TestConfig, bind: Option<String>, output: Output) -> Result<Self> {
+        let stream = socket::tcp_connect(addr, bind.as_deref(), None)?;
+        let control = stream.try_clone()
+            .wrap_err("Failed to clone control stream")?;
 
         Ok(Self {
-            args,
+            config,
+            bind,
+            output,
             control_addr: addr,
             control_stream: stream,
+            control: Arc::new(Mutex::new(control)),
         })
     }
 
+    /// Run the test and return its results
+    ///
     /// 1. TCP Upload
     /// - [ctl] Client send config to Server
     /// - [ctl] Server acknowledge
     /// - [data] Client open Nx data TCP streams
+    /// - [data] Server acknowledge each data stream
+    /// - [ctl] Client start the test when all streams are acknowledged
     /// - [data] Client send on data TCP stream
+    /// - [ctl] Client report the bytes sent during the omitted period
     /// - [ctl] Server report stats every second and when conn is closed
     ///
     /// 2. TCP Download
     /// - [ctl] Client send config to Server
     /// - [ctl] Server acknowledge
     /// - [data] Client open Nx data TCP streams
+    /// - [data] Server acknowledge each data stream
+    /// - [ctl] Client start the test when all streams are acknowledged
     /// - [data] Server send on data TCP stream
     /// - [ctl] Server report stats every second and when conn is closed
     ///
@@ -139,46 +292,167 @@ impl Client {
     /// - [ctl] Client send config to Server
     /// - [ctl] Server acknowledge
     /// - [data] Client open Nx data UDP streams
+    /// - [data] Server acknowledge each data stream
+    /// - [ctl] Client start the test when all streams are acknowledged
     /// - [data] Client send on data UDP stream
+    /// - [ctl] Client report when it stopped sending
     /// - [ctl] Server report stats every second and when conn is closed
     ///
     /// 4. UDP Download
     /// - [ctl] Client send config to Server
     /// - [ctl] Server acknowledge
     /// - [data] Client open Nx data UDP streams
+    /// - [data] Server acknowledge each data stream
+    /// - [ctl] Client start the test when all streams are acknowledged
     /// - [data] Server send on data UDP stream
     /// - [ctl] Server report stats every second and when conn is closed
     ///
-    pub fn run(&mut self) -> Result<()> {
-        let client_hello = Message::ClientHello(self.args.clone());
+    pub fn run(&mut self) -> Result<TestResults> {
+        // Fail early if the congestion control can not be set on the upload streams
+        if let Some(congestion) = self.config.congestion.as_ref().filter(|_| !self.config.revert) {
+            socket::check_congestion(congestion)?;
+        }
+
+        let client_hello = Message::ClientHello {
+            capabilities: message::capabilities(),
+            config: self.config.clone(),
+        };
         self.control_stream.sendmsg(&client_hello)
             .wrap_err("Failed to send client hello to server")?;
 
         let msg = self.control_stream.recvmsg()
             .wrap_err("Failed to read server hello message")?;
 
-        let testid = match msg {
-            Message::ServerHello(testid) => testid,
+        let (testid, capabilities, config) = match msg {
+            Message::ServerHello { testid, capabilities, config } => (testid, capabilities, config),
+            Message::ServerReject(reason) => {return Err(eyre!("Server rejected the test: {}", reason));},
             _ => {return Err(eyre!("Expected ServerHello message iso {:?}", msg));},
         };
+        let missing = message::missing_capabilities(&self.config, &capabilities);
+        if !missing.is_empty() {
+            return Err(eyre!("Server does not support {}", missing.join(", ")));
+        }
+        if config.bandwidth != self.config.bandwidth && self.output.print {
+            println!("Bandwidth limited by server to {} per stream",
+                units::format_rate(config.get_bandwidth(), self.output.format));
+        }
+        if config.time != self.config.time && self.output.print {
+            // A test bounded by a count has no duration: the server sets its maximum
+            match self.config.time {
+                0 => println!("Test stops on the count or after the server maximum of {}s", config.time),
+                _ => println!("Test duration limited by server to {}s", config.time),
+            }
+        }
+        self.config = config;
+
+        if self.output.print {
+            println!("{} {}",
+                if self.config.udp {"UDP"} else {"TCP"},
+                if self.config.bidir {"Bidirectional"} else if self.config.revert {"Download"} else {"Upload"});
+        }
 
+        // The receiver side statistics are aggregated in a dedicated thread
+        let (reports_tx, reports_rx) = channel();
+        let remote_tcp_info = capabilities.iter().any(|capability| capability == "tcp_info");
+        let aggregator = Aggregator::new(&self.config, &self.output, remote_tcp_info)?;
+        let aggregator = std::thread::spawn(move || aggregator.run(reports_rx));
+
+        let (ready_tx, ready_rx) = channel();
         let mut threads = vec!();
-        for streamid in 0 .. self.args.parallel {
-            let stream = Stream::new(self, testid, streamid);
+        let mut start_txs = vec!();
+        let mut sender_txs = vec!();
+        for streamid in 0 .. self.config.get_streams() {
+            let (start_tx, start_rx) = channel();
+            let (sender_tx, sender_rx) = channel();
+            let stream = Stream::new(self, testid, streamid, ready_tx.clone(), start_rx, reports_tx.clone(), sender_rx);
             let thread = std::thread::spawn(move || {
                 if let Err(e) = stream.run() {
-                    println!("Failed to run stream: {:?}", e);
+                    let _ = stream.ready.send(Err(e));
                 }
             });
             threads.push(thread);
+            start_txs.push(start_tx);
+            sender_txs.push(sender_tx);
+        }
+
+        // Wait for all streams to be initialized
+        for _ in 0 .. self.config.get_streams() {
+            ready_rx.recv()
+                .wrap_err("Stream thread is gone")?
+                .wrap_err("Failed to initialize all streams")?;
+        }
+
+        // Start all streams at the same time
+        self.control.lock().unwrap().sendmsg(&Message::ClientStartTest)
+            .wrap_err("Failed to send client start test to server")?;
+        let start = Instant::now();
+        let start_cpu = cpu::process_time();
+        for start_tx in start_txs {
+            let _ = start_tx.send(());
+        }
+
+        // Wait for the server reports until all streams are done
+        let timeout = Duration::from_secs(self.config.get_duration() + 10);
+        self.control_stream.set_read_timeout(Some(timeout))
+            .wrap_err("Failed to set control stream read timeout")?;
+        let mut done = 0;
+        let mut server_results = vec!();
+        while done < self.config.get_streams() {
+            let msg = self.control_stream.recvmsg()
+                .wrap_err("Failed to read server test update")?;
+            let report = match msg {
+                Message::ServerTestUpdate(report) => report,
+                Message::ServerAbort(reason) => {return Err(eyre!("Server aborted the test: {}", reason));},
+                _ => {return Err(eyre!("Expected ServerTestUpdate message iso {:?}", msg));},
+            };
+            if report.done {
+                done += 1;
+                server_results.push(aggregator::server_stats(&self.config, &report));
+            }
+            // The download streams end the omitted period after the bytes
+            // sent by the server, and stop once the server stopped sending
+            if report.sender && (report.update.omit_end || report.done) {
+                if let Some(sender_tx) = sender_txs.get(report.streamid as usize) {
+                    let _ = sender_tx.send(report.clone());
+                }
+            }
+            // On download streams, the client is the receiver and
+            // reports its own statistics: only the sender TCP_INFO
+            // statistics of the server are aggregated.
+            if report.sender || !self.config.is_download(report.streamid) {
+                let _ = reports_tx.send(report);
+            }
         }
+        drop(reports_tx);
 
+        let mut panicked = false;
         for thread in threads {
-            if let Err(e) = thread.join() {
-                println!("Thead returned an error: {:?}", e);
+            panicked |= thread.join().is_err();
+        }
+        if panicked {
+            return Err(eyre!("Stream thread panicked"));
+        }
+        // The streams failing during the test are reported once they are all done
+        if let Ok(Err(e)) = ready_rx.try_recv() {
+            return Err(e.wrap_err("Failed to run stream"));
+        }
+        let local_cpu = cpu::percent(cpu::process_time().saturating_sub(start_cpu), start.elapsed());
+        let mut results = aggregator.join()
+            .map_err(|e| eyre!("Aggregator thread returned an error: {:?}", e))?;
+        results.server = server_results;
+        results.server.sort_by_key(|stats| stats.streamid);
+        results.cpu = CpuUsage {
+            local_percent: local_cpu,
+            remote_percent: results.server.iter().map(|stats| stats.cpu_percent).sum(),
+        };
+        if self.output.print {
+            println!("CPU Utilization: local {:.1}%, remote {:.1}%",
+                results.cpu.local_percent, results.cpu.remote_percent);
+            if self.config.gso && !results.end.sum.offload {
+                println!("UDP GSO/GRO not supported: the datagrams were sent with sendmmsg() and received with recvmmsg()");
             }
         }
 
-        Ok(())
+        Ok(results)
     }
 }
@@ -0,0 +1,297 @@
+/// Test configuration sent by the client to the server
+///
+/// This is the wire representation of a speedtest: it only contains the
+/// parameters both sides need, independently of the command line options.
+/// Missing fields take their default value so that optional parameters
+/// can be added without breaking older peers.
+use eyre::{eyre, Result};
+use serde::Deserialize;
+use serde::Serialize;
+use std::time::Duration;
+use crate::pktgenerator::UDP_HEADER_LEN;
+
+/// Maximum number of parallel streams in each direction
+pub const MAX_PARALLEL: u32 = 128;
+
+/// Maximum test duration in seconds
+pub const MAX_TIME: u64 = 24 * 3600;
+
+/// Maximum target bandwidth in bits per second
+pub const MAX_BANDWIDTH: u64 = 400 * 1000 * 1000 * 1000;
+
+/// Reporting interval bounds in milliseconds
+const MIN_INTERVAL_MS: u64 = 10;
+const MAX_INTERVAL_MS: u64 = 60 * 1000;
+
+/// Maximum TCP buffer len
+const MAX_TCP_LEN: u64 = 10 * 1000 * 1000;
+
+/// Maximum UDP datagram len
+const MAX_UDP_LEN: u64 = 65507;
+
+/// Maximum length of a TCP congestion control algorithm name (TCP_CA_NAME_MAX - 1)
+const MAX_CONGESTION_LEN: usize = 15;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TestConfig {
+    /// Use UDP instead of TCP
+    pub udp: bool,
+
+    /// Download instead of Upload
+    pub revert: bool,
+
+    /// Upload and Download at the same time
+    pub bidir: bool,
+
+    /// DSCP set in the data packets IP Header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dscp: Option<u8>,
+
+    /// MARK set on the data packets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mark: Option<u32>,
+
+    /// Target bandwidth in bits per second, unlimited if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bandwidth: Option<u64>,
+
+    /// Rate limiter burst size in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub burst: Option<u64>,
+
+    /// Number of streams in each direction
+    pub parallel: u32,
+
+    /// Buffer len used to send/recv packets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub len: Option<u64>,
+
+    /// Send the TCP data with sendfile() instead of copying them from user space
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub zerocopy: bool,
+
+    /// Send the UDP datagrams with GSO and receive them with GRO.
+    /// The jitter is not measured.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub gso: bool,
+
+    /// TCP congestion control algorithm of the sending side (e.g. cubic, bbr)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub congestion: Option<String>,
+
+    /// Test duration in seconds, or 0 to only stop on the bytes or packets
+    /// count, within the maximum test duration of the server
+    pub time: u64,
+
+    /// Number of bytes to send on each stream before stopping
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+
+    /// Number of packets (TCP writes or UDP datagrams) to send
+    /// on each stream before stopping
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub packets: Option<u64>,
+
+    /// Seconds omitted from the results at the beginning of the test
+    #[serde(skip_serializing_if = "is_zero")]
+    pub omit: u64,
+
+    /// Interval between the statistics reports in milliseconds
+    pub interval_ms: u64,
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+impl Default for TestConfig {
+    fn default() -> Self {
+        Self {
+            udp: false,
+            revert: false,
+            bidir: false,
+            dscp: None,
+            mark: None,
+            bandwidth: None,
+            burst: None,
+            parallel: 1,
+            len: None,
+            zerocopy: false,
+            gso: false,
+            congestion: None,
+            time: 10,
+            bytes: None,
+            packets: None,
+            omit: 0,
+            interval_ms: 1000,
+        }
+    }
+}
+
+impl TestConfig {
+    /** Check that the test parameters are within the supported bounds */
+    pub fn validate(&self) -> Result<()> {
+        if self.revert && self.bidir {
+            return Err(eyre!("Reverse and bidirectional modes are exclusive"));
+        }
+        if self.zerocopy && self.udp {
+            return Err(eyre!("Zero-copy is only supported with TCP"));
+        }
+        if self.gso && !self.udp {
+            return Err(eyre!("GSO is only supported with UDP"));
+        }
+        if let Some(congestion) = &self.congestion {
+            if self.udp {
+                return Err(eyre!("TCP congestion control is only supported with TCP"));
+            }
+            let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+            if congestion.is_empty() || congestion.len() > MAX_CONGESTION_LEN || !congestion.chars().all(valid) {
+                return Err(eyre!("Invalid TCP congestion control algorithm {:?}", congestion));
+            }
+        }
+        if let Some(dscp) = self.dscp {
+            if dscp > 63 {
+                return Err(eyre!("Invalid DSCP value {} (0-63)", dscp));
+            }
+        }
+        if !(1..=MAX_PARALLEL).contains(&self.parallel) {
+            return Err(eyre!("Invalid number of parallel streams {} (1-{})", self.parallel, MAX_PARALLEL));
+        }
+        let min_time = match self.has_count() {
+            true => 0,
+            false => 1,
+        };
+        if !(min_time..=MAX_TIME).contains(&self.time) {
+            return Err(eyre!("Invalid test duration {}s ({}-{}s)", self.time, min_time, MAX_TIME));
+        }
+        if self.bytes == Some(0) || self.packets == Some(0) {
+            return Err(eyre!("Invalid bytes or packets count 0"));
+        }
+        if self.get_duration() > MAX_TIME {
+            return Err(eyre!("Invalid omitted duration {}s (0-{}s)", self.omit, MAX_TIME - self.time));
+        }
+        if !(MIN_INTERVAL_MS..=MAX_INTERVAL_MS).contains(&self.interval_ms) {
+            return Err(eyre!("Invalid reporting interval {}ms ({}-{}ms)", self.interval_ms, MIN_INTERVAL_MS, MAX_INTERVAL_MS));
+        }
+        if let Some(bandwidth) = self.bandwidth {
+            if bandwidth == 0 || bandwidth > MAX_BANDWIDTH {
+                return Err(eyre!("Invalid bandwidth {} bits/sec (1-{})", bandwidth, MAX_BANDWIDTH));
+            }
+        }
+        if let Some(len) = self.len {
+            let (min, max) = match self.udp {
+                true => (UDP_HEADER_LEN as u64, MAX_UDP_LEN),
+                false => (1, MAX_TCP_LEN),
+            };
+            if !(min..=max).contains(&len) {
+                return Err(eyre!("Invalid buffer len {} ({}-{})", len, min, max));
+            }
+        }
+        Ok(())
+    }
+
+    /** Return true if the test stops on a bytes or packets count */
+    pub fn has_count(&self) -> bool {
+        self.bytes.is_some() || self.packets.is_some()
+    }
+
+    /** Return the full test duration in seconds, including the omitted period */
+    pub fn get_duration(&self) -> u64 {
+        self.time.saturating_add(self.omit)
+    }
+
+    /** Return the interval between the statistics reports */
+    pub fn get_interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+
+    /** Return the test bandwidth */
+    pub fn get_bandwidth(&self) -> u64 {
+        self.bandwidth.unwrap_or(0)
+    }
+
+    /** Return the number of data streams opened for this test */
+    pub fn get_streams(&self) -> u32 {
+        match self.bidir {
+            true => 2 * self.parallel,
+            false => self.parallel,
+        }
+    }
+
+    /**
+     * Return true if the data are sent by the server on the specified stream.
+     *
+     * In bidirectional mode, the first half of the streams are used
+     * for upload and the second half for download.
+     */
+    pub fn is_download(&self, streamid: u32) -> bool {
+        match self.bidir {
+            true => streamid >= self.parallel,
+            false => self.revert,
+        }
+    }
+
+    /**
+     * Return the rate limiter burst size in bytes.
+     *
+     * An UDP burst holds at least one datagram,
+     * while the TCP writes are split to fit in the burst.
+     */
+    pub fn get_burst(&self) -> u64 {
+        let burst = self.burst.unwrap_or(self.get_bandwidth() / 8 / 100);
+        match self.udp {
+            true => std::cmp::max(burst, self.get_bufferlen()),
+            false => std::cmp::max(burst, 1),
+        }
+    }
+
+    /** Return the socket buffer len */
+    pub fn get_bufferlen(&self) -> u64 {
+        if self.udp {
+            // A datagram can not be larger than 65507 bytes
+            // and must at least contain the speednet UDP header.
+            let len = std::cmp::min(self.len.unwrap_or(1400), MAX_UDP_LEN);
+            std::cmp::max(len, UDP_HEADER_LEN as u64)
+        }
+        else {
+            let len = std::cmp::min(self.len.unwrap_or(100000), MAX_TCP_LEN);
+            std::cmp::max(len, 10)
+        }
+    }
+
+    /** Return the number of total packets to send for this test */
+    pub fn get_totalpackets(&self) -> u64 {
+        let bandwidth = match self.bandwidth {
+            Some(bandwidth) => bandwidth,
+            None => {return 0;},
+        };
+        let bufferlen = self.get_bufferlen();
+
+        self.time * bandwidth / (8 * bufferlen)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tcp_burst() {
+        let config = TestConfig { bandwidth: Some(10_000_000), ..Default::default() };
+        assert_eq!(config.get_burst(), 12_500);
+        let config = TestConfig { bandwidth: Some(8_000), ..Default::default() };
+        assert_eq!(config.get_burst(), 10);
+        let config = TestConfig { bandwidth: Some(10_000_000), burst: Some(64_000), ..Default::default() };
+        assert_eq!(config.get_burst(), 64_000);
+    }
+
+    #[test]
+    fn udp_burst() {
+        // A burst holds at least one datagram
+        let config = TestConfig { udp: true, bandwidth: Some(8_000), ..Default::default() };
+        assert_eq!(config.get_burst(), 1400);
+        let config = TestConfig { udp: true, bandwidth: Some(10_000_000), ..Default::default() };
+        assert_eq!(config.get_burst(), 12_500);
+    }
+}
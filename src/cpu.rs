@@ -0,0 +1,32 @@
+/// CPU time accounting of the data streams
+use std::time::Duration;
+
+fn cpu_time(who: libc::c_int) -> Duration {
+    let usage = unsafe {
+        let mut usage: libc::rusage = std::mem::zeroed();
+        if libc::getrusage(who, &mut usage) != 0 {
+            return Duration::ZERO;
+        }
+        usage
+    };
+    let timeval = |tv: libc::timeval| Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+    timeval(usage.ru_utime) + timeval(usage.ru_stime)
+}
+
+/// Return the user and system CPU time used by the calling thread
+pub fn thread_time() -> Duration {
+    cpu_time(libc::RUSAGE_THREAD)
+}
+
+/// Return the user and system CPU time used by the whole process
+pub fn process_time() -> Duration {
+    cpu_time(libc::RUSAGE_SELF)
+}
+
+/// Return the CPU utilization in percent of one core
+pub fn percent(cpu: Duration, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+        return 0.0;
+    }
+    100.0 * cpu.as_secs_f64() / elapsed.as_secs_f64()
+}
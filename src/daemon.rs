@@ -0,0 +1,61 @@
+/// Background server mode: detach from the terminal, pidfile and logfile
+use daemonize::Daemonize;
+use eyre::{Result, WrapErr};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Return the absolute path of the specified file.
+///
+/// The daemon working directory is / so relative paths must be resolved first.
+pub fn absolute(path: &Path) -> Result<PathBuf> {
+    std::path::absolute(path)
+        .wrap_err_with(|| format!("Invalid path {}", path.display()))
+}
+
+fn open_logfile(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .wrap_err_with(|| format!("Failed to open log file {}", path.display()))
+}
+
+/// Detach the process from the terminal.
+///
+/// The parent process exits and only the daemon returns. The standard output
+/// and error are redirected to the log file, or to /dev/null if not set.
+/// Must be called before any thread is spawned.
+pub fn daemonize(pidfile: Option<&Path>, logfile: Option<&Path>) -> Result<()> {
+    let mut daemon = Daemonize::new();
+    if let Some(pidfile) = pidfile {
+        daemon = daemon.pid_file(pidfile);
+    }
+    if let Some(logfile) = logfile {
+        let stdout = open_logfile(logfile)?;
+        let stderr = stdout.try_clone()
+            .wrap_err("Failed to duplicate log file")?;
+        daemon = daemon.stdout(stdout).stderr(stderr);
+    }
+    daemon.start()
+        .wrap_err("Failed to daemonize")
+}
+
+/// Redirect the standard output and error to the log file
+pub fn redirect_output(logfile: &Path) -> Result<()> {
+    let file = open_logfile(logfile)?;
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(file.as_raw_fd(), fd) } < 0 {
+            return Err(io::Error::last_os_error())
+                .wrap_err("Failed to redirect output to log file");
+        }
+    }
+    Ok(())
+}
+
+/// Write the process ID in the pidfile
+pub fn write_pidfile(pidfile: &Path) -> Result<()> {
+    std::fs::write(pidfile, format!("{}\n", std::process::id()))
+        .wrap_err_with(|| format!("Failed to write pid file {}", pidfile.display()))
+}
@@ -0,0 +1,42 @@
+//! speednet measures the network speed between a client and a server.
+//!
+//! The client and the server can be embedded in another program:
+//!
+//! ```no_run
+//! use speednet::{ArgsServer, Client, Server, TestConfig};
+//!
+//! let args = ArgsServer { bind: Some("127.0.0.1".into()), port: 0, ..Default::default() };
+//! let server = Server::new(args)?;
+//! let handle = server.start()?;
+//!
+//! let config = TestConfig { time: 2, ..Default::default() };
+//! let mut client = Client::connect(handle.local_addr(), config)?;
+//! let results = client.run()?;
+//! println!("{} bits/sec", results.end.sum.bits_per_second);
+//!
+//! handle.stop()?;
+//! # Ok::<(), eyre::Report>(())
+//! ```
+//!
+//! Nothing is printed by an embedded client or server: the client returns
+//! the results and the server logs are only sent to `Server::with_logger`.
+mod aggregator;
+mod batch;
+pub mod args;
+mod client;
+mod config;
+mod cpu;
+mod daemon;
+mod message;
+mod pktgenerator;
+mod server;
+mod signal;
+mod socket;
+pub mod units;
+mod viewer;
+
+pub use aggregator::{CpuUsage, Direction, IntervalStats, StreamStats, TcpStats, TestResults};
+pub use args::{ArgsClient, ArgsServer};
+pub use client::Client;
+pub use config::TestConfig;
+pub use server::{Server, ServerHandle};
@@ -1,21 +1,23 @@
 use eyre::{Result, WrapErr};
 use clap::Parser;
-use args::{Args, ArgsClient, ArgsServer, Subcommand};
-mod args;
-mod client;
-mod message;
-mod server;
-mod pktgenerator;
+use speednet::args::{Args, ArgsClient, ArgsServer, Subcommand};
+use speednet::{Client, Server};
 
 fn speednet_client(args: ArgsClient) -> Result<()> {
-    let mut client = client::Client::new(args)?;
-    client.run()
+    let json = args.json;
+    let mut client = Client::new(args)?;
+    let results = client.run()
         .wrap_err("Failed to run speednet client")?;
+    if json {
+        let json = serde_json::to_string_pretty(&results)
+            .wrap_err("Failed to stringify test results")?;
+        println!("{}", json);
+    }
     Ok(())
 }
 
 fn speednet_server(args: ArgsServer) -> Result<()> {
-    let server = server::Server::new(args)?;
+    let server = Server::new(args)?;
     server.run()
         .wrap_err("Failed to run speednet server")?;
     Ok(())
@@ -1,24 +1,65 @@
 /// Control messages sent between server and client
+///
+/// Each message is serialized in JSON and prefixed by a frame header:
+///
+/// `"SN" | version (u16) | length (u32)`
+///
+/// in network byte order, where length is the size of the JSON payload.
+/// The protocol version is checked before parsing the payload, so that
+/// mismatched client and server can reject each other cleanly.
 use eyre::{eyre, Result, WrapErr};
 use serde::Deserialize;
 use serde::Serialize;
-use crate::args::ArgsClient;
+use crate::config::TestConfig;
+use crate::pktgenerator::Update;
 use std::net::TcpStream;
 use std::io::{Read, Write};
 use std::net::UdpSocket;
+use std::fmt;
+
+/// Version of the control protocol.
+///
+/// It must be incremented on any incompatible change of the messages.
+/// Compatible additions are advertised with a capability instead.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Optional features supported by this speednet version
+pub const CAPABILITIES: &[&str] = &["udp", "reverse", "bidir", "omit", "interval", "bytes", "packets", "zerocopy", "gso", "congestion", "tcp_info"];
+
+/// Magic starting every control message frame
+const FRAME_MAGIC: [u8; 2] = *b"SN";
+
+/// Size of the frame header: magic, version and payload length
+const FRAME_HEADER_LEN: usize = 8;
+
+/// Maximum size of a control message payload
+const MAX_MESSAGE_LEN: usize = 1024 * 1024;
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 /// List of Messages used between speednet server and client
 pub enum Message {
     /// Client starts by greeting the Server with
-    /// an Hello message containing the client configuration
-    /// on the TCP control connection
-    ClientHello(ArgsClient),
+    /// an Hello message containing the client capabilities
+    /// and configuration on the TCP control connection
+    ClientHello {
+        capabilities: Vec<String>,
+        config: TestConfig,
+    },
 
     /// Server replies back by greeting the client with
-    /// an Hello message containing the Test ID
+    /// an Hello message containing the Test ID, the server
+    /// capabilities and the test configuration accepted by the server,
+    /// which may be restricted by the server limits,
     /// on the TCP control connection.
-    ServerHello(u32),
+    ServerHello {
+        testid: u64,
+        capabilities: Vec<String>,
+        config: TestConfig,
+    },
+
+    /// Server refuses the test with the specified reason
+    /// on the TCP control connection, instead of ServerHello.
+    ServerReject(String),
 
     /// Client initialize a new data stream with the server (TCP or UDP data stream).
     /// The first argument is the Test ID provided in ServerHello message.
@@ -26,7 +67,7 @@ pub enum Message {
     ///
     /// For UDP, if the client does not get a ServerInitStream reply, it may
     /// try to resend it again in order to handle packet loss.
-    ClientStreamHello(u32, u32),
+    ClientStreamHello(u64, u32),
 
     /// Server acknowledge than stream is correctly initialized
     /// on TCP or UDP data stream.
@@ -36,9 +77,154 @@ pub enum Message {
     /// on the TCP control connection.
     ClientStartTest,
 
-    /// Server send a test update to the client every second
-    /// on the TCP control connection.
-    ServerTestUpdate,
+    /// Server send a test update to the client every second for each stream
+    /// and when the stream is over on the TCP control connection.
+    ServerTestUpdate(StreamReport),
+
+    /// Client sends the report ending the omitted period of each TCP upload
+    /// stream, and the final report of each UDP upload stream, on the TCP
+    /// control connection, so that the server ends the omitted period after
+    /// the same bytes count and knows when the client stopped sending.
+    ClientTestUpdate(StreamReport),
+
+    /// Server aborts a running test with the specified reason
+    /// (e.g. on shutdown) on the TCP control connection.
+    ServerAbort(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+/// Statistics of a data stream as measured by the server
+pub struct StreamReport {
+    /// Stream ID provided in ClientStreamHello message
+    pub streamid: u32,
+
+    /// Set when the stream is over and the report contains its final results
+    pub done: bool,
+
+    /// Set on the reports of the sending side, which only
+    /// provide the TCP_INFO statistics of the stream
+    #[serde(default)]
+    pub sender: bool,
+
+    /// Stream statistics
+    pub update: Update,
+}
+
+
+/// Error returned when receiving a message from a peer
+/// using another version of the control protocol
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VersionMismatch(pub u16);
+
+impl fmt::Display for VersionMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Peer uses protocol version {} while this speednet uses version {}", self.0, PROTOCOL_VERSION)
+    }
+}
+
+impl std::error::Error for VersionMismatch {}
+
+/// Return the capabilities this speednet supports as owned strings
+pub fn capabilities() -> Vec<String> {
+    CAPABILITIES.iter().map(|capability| capability.to_string()).collect()
+}
+
+/// Return the capabilities the peer must support to run the specified test
+pub fn required_capabilities(config: &TestConfig) -> Vec<&'static str> {
+    let mut required = vec!();
+    if config.udp {
+        required.push("udp");
+    }
+    if config.revert {
+        required.push("reverse");
+    }
+    if config.bidir {
+        required.push("bidir");
+    }
+    if config.omit > 0 {
+        required.push("omit");
+    }
+    if config.interval_ms != TestConfig::default().interval_ms {
+        required.push("interval");
+    }
+    if config.bytes.is_some() {
+        required.push("bytes");
+    }
+    if config.packets.is_some() {
+        required.push("packets");
+    }
+    if config.zerocopy {
+        required.push("zerocopy");
+    }
+    if config.gso {
+        required.push("gso");
+    }
+    if config.congestion.is_some() {
+        required.push("congestion");
+    }
+    required
+}
+
+/// Return the required capabilities missing from the peer capabilities
+pub fn missing_capabilities(config: &TestConfig, capabilities: &[String]) -> Vec<&'static str> {
+    required_capabilities(config).into_iter()
+        .filter(|required| !capabilities.iter().any(|capability| capability == required))
+        .collect()
+}
+
+/// Parse a frame header and return the length of the payload
+fn decode_header(header: &[u8]) -> Result<usize> {
+    if header.len() < FRAME_HEADER_LEN || header[..2] != FRAME_MAGIC {
+        return Err(eyre!("Received data is not a speednet message"));
+    }
+    let version = u16::from_be_bytes([header[2], header[3]]);
+    if version != PROTOCOL_VERSION {
+        return Err(VersionMismatch(version).into());
+    }
+    let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(eyre!("Received message is too large: {} bytes", len));
+    }
+    Ok(len)
+}
+
+/// Parse a JSON message payload
+fn decode_payload(payload: &[u8]) -> Result<Message> {
+    let string = std::str::from_utf8(payload)
+        .wrap_err("Received message is not UTF-8")?;
+    let msg = serde_json::from_str(string)
+        .wrap_err("Failed to parse message")?;
+    Ok(msg)
+}
+
+impl Message {
+    /// Serialize a speednet control message in JSON prefixed by the frame header
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let string = serde_json::to_string(self)
+            .wrap_err("Failed to stringify message")?;
+        if string.len() > MAX_MESSAGE_LEN {
+            return Err(eyre!("Message is too large: {} bytes", string.len()));
+        }
+        let mut buff = Vec::with_capacity(FRAME_HEADER_LEN + string.len());
+        buff.extend_from_slice(&FRAME_MAGIC);
+        buff.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        buff.extend_from_slice(&(string.len() as u32).to_be_bytes());
+        buff.extend_from_slice(string.as_bytes());
+        Ok(buff)
+    }
+
+    /// Parse a speednet control message from a buffer containing a whole frame
+    pub fn decode(buff: &[u8]) -> Result<Self> {
+        let len = decode_header(buff)?;
+        let payload = buff.get(FRAME_HEADER_LEN .. FRAME_HEADER_LEN + len)
+            .ok_or(eyre!("Received message is truncated"))?;
+        decode_payload(payload)
+    }
+}
+
+/// Return true if the error was caused by a peer using another protocol version
+pub fn is_version_mismatch(error: &eyre::Report) -> bool {
+    error.chain().any(|e| e.downcast_ref::<VersionMismatch>().is_some())
 }
 
 pub trait MessageIO {
@@ -49,15 +235,11 @@ pub trait MessageIO {
 impl MessageIO for TcpStream {
     // Send a speednet control message on a TCP Stream
     //
-    // The message is stringifyied in JSON and terminated by a NULL character
+    // The message is stringifyied in JSON and prefixed by the frame header
     // before being sent on the TCP socket.
     fn sendmsg(&mut self, msg: &Message) -> Result<()> {
-        let string = serde_json::to_string(msg)
-            .wrap_err("Failed to stringify message")?;
-        let mut buff = string.into_bytes();
-        buff.push(0);
-
-        self.write(&buff)
+        let buff = msg.encode()?;
+        self.write_all(&buff)
             .wrap_err("Failed to send message")?;
         self.flush()
             .wrap_err("Failed to flush message")?;
@@ -67,79 +249,149 @@ impl MessageIO for TcpStream {
 
     // Recv a speednet control message from a TCP Stream
     //
-    // The message is received in JSON formated and is delimited by a NULL character.
+    // The frame header is read first to know the exact size of the JSON payload.
     fn recvmsg(&mut self) -> Result<Message> {
-        let mut buff = vec!(0; 4096);
-
-        // Find the message size
-        let readlen = self.peek(&mut buff)
-            .wrap_err("Failed to peek message")?;
-        if readlen == 0 {
-            return Err(eyre!("Connection closed by server"));
-        }
-        let eof = buff.iter().position(|x| *x == 0)
-            .ok_or(eyre!("Recv message has no end"))?;
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        self.read_exact(&mut header)
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::UnexpectedEof => eyre!("Connection closed by peer"),
+                _ => eyre!(e).wrap_err("Failed to read message header"),
+            })?;
+        let len = decode_header(&header)?;
 
-        // Read the exact message size
-        buff.truncate(eof + 1);
-        self.read_exact(&mut buff)
+        let mut payload = vec!(0; len);
+        self.read_exact(&mut payload)
             .wrap_err("Failed to read message")?;
-        buff.pop();
-
-        // Parse the message
-        let string = std::str::from_utf8(&buff)
-            .wrap_err("Received message is not UTF-8")?;
-        let msg = serde_json::from_str(string)
-            .wrap_err("Failed to parse message")?;
 
-        Ok(msg)
+        decode_payload(&payload)
     }
 }
 
 impl MessageIO for UdpSocket {
-    // Send a speednet control message on a TCP Stream
+    // Send a speednet control message on a connected UDP Socket
     //
-    // The message is stringifyied in JSON and terminated by a NULL character
-    // before being sent on the TCP socket.
+    // The message is stringifyied in JSON and prefixed by the frame header
+    // before being sent in a single datagram.
     fn sendmsg(&mut self, msg: &Message) -> Result<()> {
-        let string = serde_json::to_string(msg)
-            .wrap_err("Failed to stringify message")?;
-        let mut buff = string.into_bytes();
-        buff.push(0);
-
+        let buff = msg.encode()?;
         self.send(&buff)
             .wrap_err("Failed to send message")?;
 
         Ok(())
     }
 
-    // Recv a speednet control message from a TCP Stream
+    // Recv a speednet control message from a connected UDP Socket
     //
-    // The message is received in JSON formated and is delimited by a NULL character.
+    // The whole frame is received in a single datagram.
     fn recvmsg(&mut self) -> Result<Message> {
-        let mut buff = vec!(0; 4096);
+        let mut buff = vec!(0; 65536);
+        let readlen = self.recv(&mut buff)
+            .wrap_err("Failed to read message")?;
+        Message::decode(&buff[..readlen])
+    }
+}
 
-        // Find the message size
-        let readlen = self.peek(&mut buff)
-            .wrap_err("Failed to peek message")?;
-        if readlen == 0 {
-            return Err(eyre!("Connection closed by server"));
-        }
-        let eof = buff.iter().position(|x| *x == 0)
-            .ok_or(eyre!("Recv message has no end"))?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Shutdown, TcpListener};
 
-        // Read the exact message size
-        buff.truncate(eof + 1);
-        self.recv(&mut buff)
-            .wrap_err("Failed to read message")?;
-        buff.pop();
+    /// Return a connected pair of TCP streams
+    fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    /// Return a frame header with the specified version and payload length
+    fn header(version: u16, len: u32) -> Vec<u8> {
+        let mut header = FRAME_MAGIC.to_vec();
+        header.extend_from_slice(&version.to_be_bytes());
+        header.extend_from_slice(&len.to_be_bytes());
+        header
+    }
+
+    #[test]
+    fn encode_decode() {
+        let msg = Message::ClientStreamHello(0x1234, 3);
+        let buff = msg.encode().unwrap();
+        assert_eq!(buff[..FRAME_HEADER_LEN], header(PROTOCOL_VERSION, (buff.len() - FRAME_HEADER_LEN) as u32)[..]);
+        assert_eq!(Message::decode(&buff).unwrap(), msg);
+    }
+
+    #[test]
+    fn two_frames_in_one_read() {
+        let (mut client, mut server) = tcp_pair();
+        let mut buff = Message::ClientStartTest.encode().unwrap();
+        buff.extend(Message::ServerAbort("Server is shutting down".to_string()).encode().unwrap());
+        client.write_all(&buff).unwrap();
+
+        assert_eq!(server.recvmsg().unwrap(), Message::ClientStartTest);
+        assert_eq!(server.recvmsg().unwrap(), Message::ServerAbort("Server is shutting down".to_string()));
+    }
+
+    #[test]
+    fn large_frame() {
+        let (mut client, mut server) = tcp_pair();
+        let msg = Message::ServerReject("x".repeat(100000));
+        let sender = std::thread::spawn(move || client.sendmsg(&msg).map(|_| msg));
+        let received = server.recvmsg().unwrap();
+        assert_eq!(received, sender.join().unwrap().unwrap());
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let buff = Message::ClientStartTest.encode().unwrap();
+        assert!(Message::decode(&buff[..buff.len() - 1]).is_err());
+        assert!(Message::decode(&buff[..FRAME_HEADER_LEN - 1]).is_err());
+
+        let (mut client, mut server) = tcp_pair();
+        client.write_all(&buff[..buff.len() - 1]).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        assert!(server.recvmsg().is_err());
+    }
+
+    #[test]
+    fn closed_connection() {
+        let (client, mut server) = tcp_pair();
+        drop(client);
+        let error = server.recvmsg().unwrap_err();
+        assert_eq!(error.to_string(), "Connection closed by peer");
+    }
+
+    #[test]
+    fn too_large_frame() {
+        let mut buff = header(PROTOCOL_VERSION, MAX_MESSAGE_LEN as u32 + 1);
+        buff.extend_from_slice(b"{}");
+        let error = Message::decode(&buff).unwrap_err();
+        assert!(error.to_string().contains("too large"), "{}", error);
+
+        let msg = Message::ServerReject("x".repeat(MAX_MESSAGE_LEN));
+        assert!(msg.encode().is_err());
+    }
+
+    #[test]
+    fn invalid_frame() {
+        assert!(Message::decode(b"GET / HTTP/1.1\r\n").is_err());
+        let mut buff = header(PROTOCOL_VERSION, 4);
+        buff.extend_from_slice(b"{!}x");
+        assert!(Message::decode(&buff).is_err());
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let payload = serde_json::to_string(&Message::ClientStartTest).unwrap();
+        let mut buff = header(PROTOCOL_VERSION + 1, payload.len() as u32);
+        buff.extend_from_slice(payload.as_bytes());
 
-        // Parse the message
-        let string = std::str::from_utf8(&buff)
-            .wrap_err("Received message is not UTF-8")?;
-        let msg = serde_json::from_str(string)
-            .wrap_err("Failed to parse message")?;
+        let error = Message::decode(&buff).unwrap_err();
+        assert!(is_version_mismatch(&error));
+        assert_eq!(error.downcast_ref::<VersionMismatch>(), Some(&VersionMismatch(PROTOCOL_VERSION + 1)));
 
-        Ok(msg)
+        let (mut client, mut server) = tcp_pair();
+        client.write_all(&buff).unwrap();
+        assert!(is_version_mismatch(&server.recvmsg().unwrap_err()));
+        assert!(!is_version_mismatch(&Message::decode(b"SN").unwrap_err()));
     }
 }
@@ -1,94 +1,1159 @@
-use eyre::{Result, WrapErr};
-use std::time::{Instant, Duration};
+use eyre::{eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::time::{Instant, Duration, SystemTime, UNIX_EPOCH};
 use std::thread::sleep;
-use std::net::{TcpStream};
-use std::io::{Read, Write};
-use crate::args::ArgsClient;
+use std::net::{TcpStream, UdpSocket};
+use std::sync::mpsc::Receiver;
+use std::io::{Read, Write, ErrorKind};
+use socket2::SockRef;
+use crate::{
+    batch::{BatchReceiver, BatchSender},
+    config::TestConfig,
+    cpu,
+    message::StreamReport,
+    socket,
+};
 
-#[derive(Debug, Clone, PartialEq, Default)]
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
 pub struct Update {
     pub elapsed: Duration,
     pub pktcount_expected: u64,
     pub pktcount: u64,
     pub bytes: u64,
+
+    /// UDP datagrams never received
+    pub pktlost: u64,
+    /// UDP datagrams received after a datagram with a higher sequence number
+    pub pktoutoforder: u64,
+    /// UDP datagrams received more than once
+    pub pktduplicate: u64,
+    /// UDP interarrival jitter (RFC 3550)
+    pub jitter: Duration,
+
+    /// UDP datagrams sent with GSO or received with GRO
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub offload: bool,
+
+    /// Statistics of the omitted period at the beginning of the test
+    #[serde(default)]
+    pub omitted: bool,
+
+    /// Set on the report ending the omitted period: the measured period
+    /// starts after the bytes of this report
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub omit_end: bool,
+
+    /// CPU time used by the stream
+    #[serde(default)]
+    pub cpu: Duration,
+
+    /// TCP_INFO statistics, only sampled by the sending side of TCP streams
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp: Option<TcpInfo>,
+
+    /// Statistics of the last interval only, set on the interval reports
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<Box<Update>>,
 }
 
 impl Update {
     pub fn get_througtput(&self) -> u64 {
-        8 * ((1000000 * self.bytes) / self.elapsed.as_micros() as u64)
+        let elapsed = self.elapsed.as_micros();
+        if elapsed == 0 {
+            return 0;
+        }
+        // Computed on 128 bits: the bytes count of a long test overflows 64 bits once scaled
+        (8 * ((1000000 * self.bytes as u128) / elapsed)) as u64
+    }
+
+    /// Return the statistics accumulated since the specified update.
+    ///
+    /// The jitter and the TCP_INFO gauges are not cumulative and are kept as is.
+    pub fn since(&self, prev: &Update) -> Update {
+        Update {
+            elapsed: self.elapsed.saturating_sub(prev.elapsed),
+            pktcount_expected: self.pktcount_expected.saturating_sub(prev.pktcount_expected),
+            pktcount: self.pktcount.saturating_sub(prev.pktcount),
+            bytes: self.bytes.saturating_sub(prev.bytes),
+            pktlost: self.pktlost.saturating_sub(prev.pktlost),
+            pktoutoforder: self.pktoutoforder.saturating_sub(prev.pktoutoforder),
+            pktduplicate: self.pktduplicate.saturating_sub(prev.pktduplicate),
+            jitter: self.jitter,
+            offload: self.offload,
+            omitted: self.omitted,
+            omit_end: self.omit_end,
+            cpu: self.cpu.saturating_sub(prev.cpu),
+            tcp: self.tcp.map(|tcp| TcpInfo {
+                retransmits: tcp.retransmits.saturating_sub(prev.tcp.map_or(0, |prev| prev.retransmits)),
+                ..tcp
+            }),
+            interval: None,
+        }
+    }
+
+    /// Return the UDP packet loss in percent
+    pub fn get_loss_percent(&self) -> f64 {
+        let total = self.pktcount + self.pktlost;
+        if total == 0 {
+            return 0.0;
+        }
+        100.0 * self.pktlost as f64 / total as f64
+    }
+}
+
+/// TCP statistics of a sending stream, sampled with TCP_INFO
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+pub struct TcpInfo {
+    /// Smoothed round-trip time
+    pub rtt: Duration,
+    /// Round-trip time mean deviation
+    pub rttvar: Duration,
+    /// Congestion window in bytes
+    pub cwnd: u64,
+    /// Segments retransmitted
+    pub retransmits: u64,
+    /// Pacing rate in bits per second
+    pub pacing_rate: u64,
+    /// Most recent delivery rate in bits per second
+    pub delivery_rate: u64,
+}
+
+impl TcpInfo {
+    /// Sample the TCP_INFO statistics of the stream, counting
+    /// the retransmits from the specified retransmits total
+    fn sample(stream: &TcpStream, retransmits_base: u64) -> Option<Self> {
+        let info = socket::tcp_info(stream).ok()?;
+        Some(Self {
+            rtt: Duration::from_micros(info.tcpi_rtt as u64),
+            rttvar: Duration::from_micros(info.tcpi_rttvar as u64),
+            cwnd: info.tcpi_snd_cwnd as u64 * info.tcpi_snd_mss as u64,
+            retransmits: (info.tcpi_total_retrans as u64).saturating_sub(retransmits_base),
+            pacing_rate: info.tcpi_pacing_rate.saturating_mul(8),
+            delivery_rate: info.tcpi_delivery_rate.saturating_mul(8),
+        })
+    }
+}
+
+/// Length of the header stamped at the beginning of each UDP datagram
+pub const UDP_HEADER_LEN: usize = 24;
+
+/// Magic number identifying a speednet UDP datagram ("SPDN")
+const UDP_MAGIC: u32 = 0x5350444e;
+
+/// Flag set in the stream ID field of the datagrams sent after the omitted period
+const UDP_MEASURED_FLAG: u32 = 1 << 31;
+
+/// Header stamped at the beginning of each UDP datagram.
+///
+/// All fields are encoded in network byte order.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct UdpHeader {
+    pub streamid: u32,
+    /// Sent after the omitted period
+    pub measured: bool,
+    pub seqno: u64,
+    /// Send timestamp in micro seconds since UNIX epoch
+    pub timestamp: u64,
+}
+
+impl UdpHeader {
+    fn write(&self, buffer: &mut [u8]) {
+        let streamid = match self.measured {
+            true => self.streamid | UDP_MEASURED_FLAG,
+            false => self.streamid,
+        };
+        buffer[0..4].copy_from_slice(&UDP_MAGIC.to_be_bytes());
+        buffer[4..8].copy_from_slice(&streamid.to_be_bytes());
+        buffer[8..16].copy_from_slice(&self.seqno.to_be_bytes());
+        buffer[16..24].copy_from_slice(&self.timestamp.to_be_bytes());
+    }
+
+    fn read(buffer: &[u8]) -> Option<Self> {
+        if buffer.len() < UDP_HEADER_LEN {
+            return None;
+        }
+        if buffer[0..4] != UDP_MAGIC.to_be_bytes() {
+            return None;
+        }
+        let streamid = u32::from_be_bytes(buffer[4..8].try_into().ok()?);
+        Some(Self {
+            streamid: streamid & !UDP_MEASURED_FLAG,
+            measured: streamid & UDP_MEASURED_FLAG != 0,
+            seqno: u64::from_be_bytes(buffer[8..16].try_into().ok()?),
+            timestamp: u64::from_be_bytes(buffer[16..24].try_into().ok()?),
+        })
+    }
+}
+
+/// Number of sequence numbers remembered to detect duplicates
+const SEQ_WINDOW: u64 = 65536;
+
+/// Track the sequence numbers and the transit time of the received
+/// UDP datagrams to compute loss, reordering, duplicates and jitter.
+struct UdpAccounting {
+    next_seqno: u64,
+    received: Vec<bool>,
+    prev_transit: Option<i64>,
+    jitter: f64,
+}
+
+impl UdpAccounting {
+    fn new() -> Self {
+        Self {
+            next_seqno: 0,
+            received: vec!(false; SEQ_WINDOW as usize),
+            prev_transit: None,
+            jitter: 0.0,
+        }
+    }
+
+    /// Account a received datagram. Return false if it is a duplicate
+    /// or if its sequence number is invalid.
+    fn account(&mut self, header: &UdpHeader, update: &mut Update) -> bool {
+        let seqno = header.seqno;
+        if seqno >= self.next_seqno {
+            // The last sequence number can not be followed by another one
+            let next_seqno = match seqno.checked_add(1) {
+                Some(next_seqno) => next_seqno,
+                None => {return false;},
+            };
+            // Every sequence number skipped is considered lost until received
+            let first = std::cmp::max(self.next_seqno, next_seqno.saturating_sub(SEQ_WINDOW));
+            for i in first..seqno {
+                self.received[(i % SEQ_WINDOW) as usize] = false;
+            }
+            update.pktlost += seqno - self.next_seqno;
+            self.next_seqno = next_seqno;
+        }
+        else if self.next_seqno - seqno >= SEQ_WINDOW {
+            // Too late to know if it is a duplicate
+            update.pktlost = update.pktlost.saturating_sub(1);
+            update.pktoutoforder += 1;
+        }
+        else if self.received[(seqno % SEQ_WINDOW) as usize] {
+            update.pktduplicate += 1;
+            return false;
+        }
+        else {
+            // The loss may have been accounted before the counters were reset
+            update.pktlost = update.pktlost.saturating_sub(1);
+            update.pktoutoforder += 1;
+        }
+        if self.next_seqno - seqno < SEQ_WINDOW {
+            self.received[(seqno % SEQ_WINDOW) as usize] = true;
+        }
+
+        // Interarrival jitter as defined in RFC 3550 section 6.4.1
+        let transit = timestamp() as i64 - header.timestamp as i64;
+        if let Some(prev_transit) = self.prev_transit {
+            let d = (transit - prev_transit).abs() as f64;
+            self.jitter += (d - self.jitter) / 16.0;
+            update.jitter = Duration::from_micros(self.jitter as u64);
+        }
+        self.prev_transit = Some(transit);
+
+        true
+    }
+}
+
+/// Maximum time blocked on a socket before checking the interval timer
+const MAX_TIMER_RESOLUTION: Duration = Duration::from_millis(10);
+
+/// Report the statistics of a data stream at the end of each interval,
+/// independently of the socket activity.
+///
+/// The statistics of the omitted period at the beginning of the test
+/// (e.g. TCP slow start) are reported, then the clock and the counters
+/// restart from zero. The sender ends the omitted period on its clock
+/// and the receiver where the sender did in the stream, so that both
+/// sides measure the same data.
+struct IntervalTimer {
+    interval: Duration,
+    /// Test duration, excluding the omitted period
+    duration: Duration,
+    /// Bytes and packets counts stopping the test, excluding the omitted period
+    bytes: Option<u64>,
+    packets: Option<u64>,
+    omit: Duration,
+    omitted: bool,
+    /// The omitted period is ended by end_omit() rather than on the clock
+    receiver: bool,
+    start: Instant,
+    /// CPU time used by the thread at the start of the test
+    start_cpu: Duration,
+    /// End of the current interval
+    next: Duration,
+    /// Statistics at the beginning of the current interval
+    prev: Update,
+}
+
+impl IntervalTimer {
+    fn new(config: &TestConfig) -> Self {
+        Self {
+            interval: config.get_interval(),
+            duration: Duration::from_secs(config.time),
+            bytes: config.bytes,
+            packets: config.packets,
+            omit: Duration::from_secs(config.omit),
+            omitted: config.omit > 0,
+            receiver: false,
+            start: Instant::now(),
+            start_cpu: cpu::thread_time(),
+            next: config.get_interval(),
+            prev: Update::default(),
+        }
+    }
+
+    /// Create the timer of a receiver, which ends the omitted period
+    /// where the sender did with end_omit()
+    fn receiver(config: &TestConfig) -> Self {
+        Self {
+            receiver: true,
+            ..Self::new(config)
+        }
+    }
+
+    /// Return the socket timeout needed to report the intervals on time
+    fn resolution(&self) -> Duration {
+        std::cmp::min(self.interval / 20, MAX_TIMER_RESOLUTION)
+    }
+
+    /// Return the time elapsed since the start of the test,
+    /// or since the end of the omitted period
+    fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Return true if the test duration is over.
+    ///
+    /// A receiver still waiting for the end of the omitted period gives up
+    /// once the omitted period and the test duration are over.
+    fn is_over(&self, elapsed: Duration) -> bool {
+        match self.omitted {
+            true => self.receiver && elapsed >= self.omit + self.duration,
+            false => elapsed >= self.duration,
+        }
+    }
+
+    /// Return true during the omitted period
+    fn is_omitted(&self) -> bool {
+        self.omitted
+    }
+
+    /// Return true if the omitted period is over on the clock
+    fn is_omit_over(&self, elapsed: Duration) -> bool {
+        self.omitted && elapsed >= self.omit
+    }
+
+    /// Return true if the bytes or the packets count of the test is reached
+    fn count_reached(&self, bytes: u64, packets: u64) -> bool {
+        !self.omitted && (self.bytes.is_some_and(|max| bytes >= max) || self.packets.is_some_and(|max| packets >= max))
+    }
+
+    /// Return the number of packets left to send before reaching the packets count
+    fn remaining_packets(&self, packets: u64) -> u64 {
+        match (self.omitted, self.packets) {
+            (false, Some(max)) => max.saturating_sub(packets),
+            _ => u64::MAX,
+        }
+    }
+
+    /// Return the number of bytes left to send before reaching the bytes count
+    fn remaining_bytes(&self, bytes: u64) -> u64 {
+        match (self.omitted, self.bytes) {
+            (false, Some(max)) => max.saturating_sub(bytes),
+            _ => u64::MAX,
+        }
+    }
+
+    /// Return true if the current interval is over and is reported by tick()
+    fn is_due(&self, elapsed: Duration) -> bool {
+        match self.omitted {
+            // The last omitted interval of a receiver is reported by end_omit()
+            true if self.receiver => elapsed >= self.next && self.next < self.omit,
+            true => elapsed >= self.omit || elapsed >= self.next,
+            // The intervals ending after the test duration are reported by finish()
+            false => elapsed >= self.next && self.next <= self.duration,
+        }
+    }
+
+    /// Report the current interval if it is over.
+    ///
+    /// Return true if the counters were restarted at the end of the omitted period.
+    fn tick<F: FnMut(&Update)>(&mut self, update: &mut Update, update_cb: &mut F) -> bool {
+        update.omitted = self.omitted;
+        if !self.is_due(update.elapsed) {
+            return false;
+        }
+        if !self.receiver && self.is_omit_over(update.elapsed) {
+            self.end_omit(update, update_cb);
+            return true;
+        }
+        self.report(update, update_cb);
+        // Skip the intervals missed while blocked
+        while self.next <= update.elapsed {
+            self.next += self.interval;
+        }
+        false
+    }
+
+    /// Report the last interval of the omitted period,
+    /// then restart the clock and the counters.
+    fn end_omit<F: FnMut(&Update)>(&mut self, update: &mut Update, update_cb: &mut F) {
+        update.omitted = true;
+        update.omit_end = true;
+        self.report(update, update_cb);
+        self.start = Instant::now();
+        self.start_cpu = cpu::thread_time();
+        self.next = self.interval;
+        self.omitted = false;
+        self.prev = Update::default();
+        *update = Update::default();
+    }
+
+    /// Report the last partial interval when the stream is over,
+    /// unless it is too short to be meaningful.
+    fn finish<F: FnMut(&Update)>(&mut self, update: &mut Update, update_cb: &mut F) {
+        update.cpu = cpu::thread_time().saturating_sub(self.start_cpu);
+        let elapsed = update.elapsed.saturating_sub(self.prev.elapsed);
+        if elapsed >= self.interval / 10 && update.pktcount > self.prev.pktcount {
+            self.report(update, update_cb);
+        }
+    }
+
+    fn report<F: FnMut(&Update)>(&mut self, update: &mut Update, update_cb: &mut F) {
+        update.cpu = cpu::thread_time().saturating_sub(self.start_cpu);
+        let mut report = update.clone();
+        report.interval = Some(Box::new(update.since(&self.prev)));
+        update_cb(&report);
+        self.prev = update.clone();
+    }
+}
+
+/// Return the current time in micro seconds since UNIX epoch
+fn timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+/// Maximum time spent sleeping by the rate limiter at once, so that
+/// the senders still report their statistics on time.
+const MAX_PACING_SLEEP: Duration = Duration::from_millis(10);
+
+/// Token bucket rate limiter pacing the senders on bytes.
+///
+/// Tokens are bytes credited at the target bandwidth up to the burst size.
+/// A sender may always send when the bucket is not empty and the bytes sent
+/// are then debited, possibly leaving the bucket in debt until it is refilled.
+pub struct RateLimiter {
+    /// Target bandwidth in bits per second (0 means unlimited)
+    bandwidth: u64,
+    /// Maximum number of bytes credited while the sender is idle
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bandwidth: u64, burst: u64) -> Self {
+        Self {
+            bandwidth,
+            burst: burst as f64,
+            tokens: 0.0,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = f64::min(self.tokens + elapsed * self.bandwidth as f64 / 8.0, self.burst);
+        self.last_refill = now;
+    }
+
+    /// Return the time to wait before sending, or None if the sender can send now.
+    pub fn delay(&mut self) -> Option<Duration> {
+        self.delay_at(Instant::now())
+    }
+
+    /// Return the time to wait at the specified time before sending,
+    /// or None if the sender can send then.
+    fn delay_at(&mut self, now: Instant) -> Option<Duration> {
+        if self.bandwidth == 0 {
+            return None;
+        }
+        self.refill(now);
+        if self.tokens >= 0.0 {
+            return None;
+        }
+        let delay = -self.tokens * 8.0 / self.bandwidth as f64;
+        Some(Duration::from_secs_f64(delay))
+    }
+
+    /// Wait, up to MAX_PACING_SLEEP, until the sender can send.
+    /// Return true if the sender can send now.
+    pub fn wait(&mut self) -> bool {
+        match self.delay() {
+            Some(delay) => {
+                sleep(std::cmp::min(delay, MAX_PACING_SLEEP));
+                false
+            },
+            None => true,
+        }
+    }
+
+    /// Debit the bytes sent
+    pub fn consume(&mut self, len: u64) {
+        if self.bandwidth > 0 {
+            self.tokens -= len as f64;
+        }
+    }
+}
+
+/// Maximum time without any progress on a TCP data stream
+/// before giving up on the peer, on top of the pacing gaps
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of gaps between the paced sends tolerated on top of PEER_TIMEOUT
+const PEER_TIMEOUT_GAPS: u32 = 4;
+
+/// Return the longest gap between two sends paced by the rate limiter,
+/// which sends at most a burst at once
+fn pacing_gap(config: &TestConfig) -> Duration {
+    match config.get_bandwidth() {
+        0 => Duration::ZERO,
+        bandwidth => Duration::from_secs_f64(config.get_burst() as f64 * 8.0 / bandwidth as f64),
     }
 }
 
-pub fn tcp_send<F: FnMut(&Update)>(args: &ArgsClient, mut stream: TcpStream, mut update_cb: F) -> Result<Update> {
-    let bufferlen = args.get_bufferlen();
+/// Return the maximum time without any progress on a TCP data stream
+fn peer_timeout(config: &TestConfig) -> Duration {
+    PEER_TIMEOUT + PEER_TIMEOUT_GAPS * pacing_gap(config)
+}
+
+/// Return true if the I/O error is caused by a socket timeout
+fn is_timeout(e: &std::io::Error) -> bool {
+    e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut
+}
+
+pub fn tcp_send<F: FnMut(&Update)>(config: &TestConfig, mut stream: TcpStream, mut update_cb: F) -> Result<Update> {
+    let bufferlen = config.get_bufferlen();
     let mut buffer = Vec::with_capacity(bufferlen as usize);
-    for i in 1..bufferlen {
+    for i in 0..bufferlen {
         let value : u64 = i % 255;
         buffer.push(value as u8);
     }
-    let duration = Duration::from_secs(args.time);
-    let bandwidth = args.get_bandwidth();
-    let total_packets = args.get_totalpackets();
-    let now = Instant::now();
+    let duration = Duration::from_secs(config.time);
+    let total_packets = config.get_totalpackets();
+    let mut limiter = RateLimiter::new(config.get_bandwidth(), config.get_burst());
+    let mut timer = IntervalTimer::new(config);
 
-    println!("Duration: {}", args.time);
-    println!("Bandwidth: {}", bandwidth);
-    println!("Bufferlen: {}", bufferlen);
-    println!("Total packets: {}", total_packets);
-    println!("");
+    // The writes are paced up to the burst size of the rate limiter
+    let max_len = match config.get_bandwidth() {
+        0 => buffer.len() as u64,
+        _ => std::cmp::min(buffer.len() as u64, config.get_burst()),
+    };
+
+    if let Some(congestion) = &config.congestion {
+        socket::set_congestion(SockRef::from(&stream), congestion)?;
+    }
+
+    // In zero-copy mode, the kernel sends the pages of an in-memory file
+    let zerocopy = match config.zerocopy {
+        true => Some(socket::memfd(&buffer)?),
+        false => None,
+    };
+
+    // Do not block past the end of the interval, while still giving up
+    // if the peer stopped reading
+    let peer_timeout = peer_timeout(config);
+    stream.set_write_timeout(Some(timer.resolution()))
+        .wrap_err("Failed to set write timeout")?;
+    let mut last_write = Instant::now();
+
+    // The TCP_INFO statistics are sampled at the end of each interval,
+    // and the retransmits of the omitted period are not accounted
+    let mut retransmits_base = 0;
 
     let mut update = Update::default();
-    let mut prev_elapsed = Duration::from_secs(0);
     loop {
-        update.elapsed = now.elapsed();
+        update.elapsed = timer.elapsed();
         update.pktcount_expected = ((total_packets as u128 * update.elapsed.as_nanos()) / duration.as_nanos()) as u64;
-        if update.elapsed.as_secs() != prev_elapsed.as_secs() {
-            update_cb(&update);
-            prev_elapsed = update.elapsed;
+        if timer.is_due(update.elapsed) {
+            update.tcp = TcpInfo::sample(&stream, retransmits_base);
         }
-        if update.elapsed.as_secs() >= args.time {
+        if timer.tick(&mut update, &mut update_cb) {
+            retransmits_base = TcpInfo::sample(&stream, 0).map_or(0, |tcp| tcp.retransmits);
+        }
+        if timer.is_over(update.elapsed) || timer.count_reached(update.bytes, update.pktcount) {
             break;
         }
-        if (bandwidth > 0) && (update.pktcount >= update.pktcount_expected) {
-            sleep(Duration::from_millis(1));
+        if !limiter.wait() {
             continue;
         }
 
-        let len = stream.write(&mut buffer)?;
+        // The last write is truncated to the bytes count
+        let len = std::cmp::min(max_len, timer.remaining_bytes(update.bytes)) as usize;
+        let result = match &zerocopy {
+            Some(file) => socket::sendfile(&stream, file, len),
+            None => stream.write(&buffer[..len]),
+        };
+        let len = match result {
+            Ok(len) => len,
+            Err(e) if is_timeout(&e) => {
+                if last_write.elapsed() >= peer_timeout {
+                    return Err(eyre!("Peer did not read any data for {:?}", peer_timeout));
+                }
+                continue;
+            },
+            Err(e) => {
+                return Err(e).wrap_err("Failed to write");
+            },
+        };
+        // The peer closed the connection
         if len == 0 {
-            println!("Connection to server closed");
             break;
         }
+        last_write = Instant::now();
+        limiter.consume(len as u64);
         update.pktcount += 1;
         update.bytes += len as u64;
     }
+    update.tcp = TcpInfo::sample(&stream, retransmits_base);
+    timer.finish(&mut update, &mut update_cb);
     Ok(update)
 }
 
-pub fn tcp_recv<F: FnMut(&Update)>(args: &ArgsClient, mut stream: TcpStream, mut update_cb: F) -> Result<Update> {
+/// Return the burst size of the rate limiter of a receiver limited to
+/// max_bandwidth, at least 10ms at this bandwidth
+fn receiver_burst(config: &TestConfig, max_bandwidth: u64) -> u64 {
+    std::cmp::max(config.get_burst(), max_bandwidth / 8 / 100)
+}
+
+/// Receive the data of a TCP stream, reading them at most at max_bandwidth
+/// bits per second (0 means unlimited) to throttle the sender.
+///
+/// The omitted period ends after the bytes count the sender sent during
+/// its omitted period, given by the sender report ending it.
+pub fn tcp_recv<F: FnMut(&Update)>(config: &TestConfig, max_bandwidth: u64, sender: &Receiver<StreamReport>, mut stream: TcpStream, mut update_cb: F) -> Result<Update> {
     let mut update = Update::default();
-    let bufferlen = args.get_bufferlen();
+    let bufferlen = config.get_bufferlen();
     let mut buffer = vec!(0; bufferlen as usize);
-    let now = Instant::now();
+    let mut timer = IntervalTimer::receiver(config);
+    let mut omit_bytes = None;
+
+    // The reads are paced up to the burst size of the rate limiter,
+    // the TCP window closing on the sender once the socket buffer is full
+    let burst = receiver_burst(config, max_bandwidth);
+    let mut limiter = RateLimiter::new(max_bandwidth, burst);
+    let max_len = match max_bandwidth {
+        0 => buffer.len(),
+        _ => std::cmp::min(buffer.len() as u64, burst) as usize,
+    };
+
+    // Do not block past the end of the interval, while still giving up
+    // if the peer stopped sending
+    let peer_timeout = peer_timeout(config);
+    stream.set_read_timeout(Some(timer.resolution()))
+        .wrap_err("Failed to set read timeout")?;
+    let mut last_read = Instant::now();
 
-    let mut prev_elapsed = Duration::from_secs(0);
     loop {
-        update.elapsed = now.elapsed();
-        if update.elapsed.as_secs() != prev_elapsed.as_secs() {
-            update_cb(&update);
-            prev_elapsed = update.elapsed;
+        update.elapsed = timer.elapsed();
+        timer.tick(&mut update, &mut update_cb);
+        if timer.is_omitted() {
+            if omit_bytes.is_none() {
+                omit_bytes = sender.try_iter()
+                    .find(|report| report.update.omit_end)
+                    .map(|report| report.update.bytes);
+            }
+            // The bytes read before the omitted bytes count was known are measured
+            if let Some(omit_bytes) = omit_bytes.filter(|omit_bytes| update.bytes >= *omit_bytes) {
+                let measured = update.bytes - omit_bytes;
+                update.bytes = omit_bytes;
+                timer.end_omit(&mut update, &mut update_cb);
+                update.bytes = measured;
+                update.pktcount = std::cmp::min(measured, 1);
+            }
+        }
+        if !limiter.wait() {
+            continue;
         }
 
-        let len = stream.read(&mut buffer)
-            .wrap_err("Failed to read")?;
+        // Do not read past the omitted bytes count once known
+        let len = match omit_bytes.filter(|_| timer.is_omitted()) {
+            Some(omit_bytes) => std::cmp::min(max_len as u64, omit_bytes - update.bytes) as usize,
+            None => max_len,
+        };
+        let len = match stream.read(&mut buffer[..len]) {
+            Ok(len) => len,
+            Err(e) if is_timeout(&e) => {
+                if last_read.elapsed() >= peer_timeout {
+                    return Err(eyre!("Peer did not send any data for {:?}", peer_timeout));
+                }
+                continue;
+            },
+            Err(e) => {
+                return Err(e).wrap_err("Failed to read");
+            },
+        };
 
         if len == 0 {
             break;
         }
+        last_read = Instant::now();
+        limiter.consume(len as u64);
         update.pktcount += 1;
         update.bytes += len as u64;
+
+        // The reads do not match the writes of the sender:
+        // only the bytes count is checked.
+        if timer.count_reached(update.bytes, 0) {
+            break;
+        }
     }
 
+    update.elapsed = timer.elapsed();
+    timer.finish(&mut update, &mut update_cb);
     Ok(update)
 }
 
+pub fn udp_send<F: FnMut(&Update)>(config: &TestConfig, streamid: u32, socket: UdpSocket, mut update_cb: F) -> Result<Update> {
+    let bufferlen = config.get_bufferlen();
+    let mut buffer = Vec::with_capacity(bufferlen as usize);
+    for i in 0..bufferlen {
+        let value : u64 = i % 255;
+        buffer.push(value as u8);
+    }
+    let duration = Duration::from_secs(config.time);
+    let total_packets = config.get_totalpackets();
+    let mut limiter = RateLimiter::new(config.get_bandwidth(), config.get_burst());
+    let mut timer = IntervalTimer::new(config);
+
+    // The datagrams are sent by batches, up to the burst size of the rate limiter
+    let len = bufferlen as usize;
+    let mut sender = BatchSender::new(&socket, len, config.gso);
+    let max_batch = match config.get_bandwidth() {
+        0 => sender.max_batch(len),
+        _ => (config.get_burst() as usize / len).clamp(1, sender.max_batch(len)),
+    };
+    let mut buffer = buffer.repeat(max_batch);
+
+    let mut update = Update::default();
+    let mut seqno = 0;
+    loop {
+        update.elapsed = timer.elapsed();
+        update.pktcount_expected = ((total_packets as u128 * update.elapsed.as_nanos()) / duration.as_nanos()) as u64;
+        // GSO falls back to sendmmsg() if the output device can not segment the datagrams
+        update.offload = sender.is_gso();
+        if timer.tick(&mut update, &mut update_cb) {
+            seqno = 0;
+        }
+        if timer.is_over(update.elapsed) || timer.count_reached(update.bytes, update.pktcount) {
+            break;
+        }
+        if !limiter.wait() {
+            continue;
+        }
+
+        // Do not send more datagrams than the bytes or packets count
+        let remaining = std::cmp::min(
+            timer.remaining_packets(update.pktcount),
+            timer.remaining_bytes(update.bytes).div_ceil(len as u64));
+        let batch = std::cmp::min(max_batch as u64, remaining) as usize;
+
+        // The sequence numbers restart from zero after the omitted period,
+        // the receiver telling the measured datagrams apart by their flag
+        let now = timestamp();
+        for (i, datagram) in buffer.chunks_mut(len).take(batch).enumerate() {
+            let header = UdpHeader {
+                streamid,
+                measured: !timer.is_omitted(),
+                seqno: seqno + i as u64,
+                timestamp: now,
+            };
+            header.write(datagram);
+        }
+
+        // The peer closing its socket before the end of the test is an error
+        let sent = match sender.send(&socket, &buffer[..batch * len], len) {
+            Ok(sent) => sent as u64,
+            Err(e) => {
+                return Err(e).wrap_err("Failed to send datagrams");
+            },
+        };
+        seqno += sent;
+        limiter.consume(sent * len as u64);
+        update.pktcount += sent;
+        update.bytes += sent * len as u64;
+    }
+    timer.finish(&mut update, &mut update_cb);
+    Ok(update)
+}
+
+/// Time without any datagram after the test duration, or after the
+/// peer reported it stopped sending, before ending the stream
+const UDP_END_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Return the time without any datagram after the test duration before
+/// ending the stream, the last datagrams being paced by the rate limiter
+fn udp_end_timeout(config: &TestConfig) -> Duration {
+    UDP_END_TIMEOUT + pacing_gap(config)
+}
+
+/// Time the datagrams received may be ahead of the maximum bandwidth
+/// before the peer is considered to exceed it
+const UDP_MAX_BANDWIDTH_SLACK: Duration = Duration::from_secs(1);
+
+/// Receive the datagrams of an UDP stream, failing if they are received
+/// faster than max_bandwidth bits per second (0 means unlimited).
+///
+/// The stream stops on the bytes or packets count, or once the sender
+/// reported it stopped sending, or after the test duration.
+pub fn udp_recv<F: FnMut(&Update)>(config: &TestConfig, max_bandwidth: u64, sender: &Receiver<StreamReport>, streamid: u32, socket: UdpSocket, mut update_cb: F) -> Result<Update> {
+    let mut update = Update::default();
+    let mut policer = RateLimiter::new(max_bandwidth, receiver_burst(config, max_bandwidth));
+    let mut accounting = UdpAccounting::new();
+    let mut receiver = BatchReceiver::new(&socket, config.get_bufferlen() as usize, config.gso);
+    let mut timer = IntervalTimer::receiver(config);
+
+    // UDP has no end of stream: the test is over when the test duration is
+    // elapsed or the sender is done, and the peer stopped sending.
+    socket.set_read_timeout(Some(timer.resolution()))
+        .wrap_err("Failed to set read timeout")?;
+    let end_timeout = udp_end_timeout(config);
+    let mut sender_done = false;
+
+    let mut last_recv = Duration::from_secs(0);
+    loop {
+        update.elapsed = timer.elapsed();
+        update.offload = receiver.is_gro();
+        timer.tick(&mut update, &mut update_cb);
+
+        match receiver.recv(&socket) {
+            // speednet never sends empty datagrams: the socket was shut down
+            Ok(0) => {break;},
+            Ok(_) => {},
+            Err(e) if is_timeout(&e) => {
+                sender_done |= sender.try_iter().any(|report| report.done);
+                let idle = update.elapsed.saturating_sub(last_recv);
+                if (sender_done && idle >= UDP_END_TIMEOUT) || (timer.is_over(update.elapsed) && idle >= end_timeout) {
+                    // Do not account the final idle time in the test duration
+                    update.elapsed = last_recv;
+                    break;
+                }
+                continue;
+            },
+            // The peer closed its socket after the end of the test
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused && timer.is_over(update.elapsed) => {
+                break;
+            },
+            Err(e) => {
+                return Err(e).wrap_err("Failed to read");
+            },
+        };
+
+        // Each datagram of the batch is accounted on its own
+        let mut received = 0;
+        for datagram in receiver.datagrams() {
+            // Ignore datagrams not belonging to this stream (e.g. control messages)
+            let header = match UdpHeader::read(datagram) {
+                Some(header) if header.streamid == streamid => header,
+                _ => {continue;},
+            };
+            received += datagram.len() as u64;
+            // The first measured datagram ends the omitted period,
+            // and the late omitted datagrams are no longer accounted
+            match (header.measured, timer.is_omitted()) {
+                (true, true) => {
+                    update.elapsed = timer.elapsed();
+                    timer.end_omit(&mut update, &mut update_cb);
+                    accounting = UdpAccounting::new();
+                },
+                (false, false) => {continue;},
+                _ => {},
+            }
+            if !accounting.account(&header, &mut update) {
+                continue;
+            }
+            update.pktcount += 1;
+            update.bytes += datagram.len() as u64;
+        }
+        if received == 0 {
+            continue;
+        }
+        last_recv = timer.elapsed();
+        policer.consume(received);
+        if policer.delay().is_some_and(|delay| delay > UDP_MAX_BANDWIDTH_SLACK) {
+            return Err(eyre!("Peer exceeded the maximum bandwidth of {} bits/sec", max_bandwidth));
+        }
+
+        // The lost datagrams are part of the packets count
+        if timer.count_reached(update.bytes, update.pktcount + update.pktlost) {
+            update.elapsed = last_recv;
+            break;
+        }
+    }
+
+    timer.finish(&mut update, &mut update_cb);
+    Ok(update)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Account the specified sequence numbers on a new stream
+    fn account(seqnos: &[u64]) -> (Update, Vec<bool>) {
+        let mut accounting = UdpAccounting::new();
+        let mut update = Update::default();
+        let accounted = seqnos.iter()
+            .map(|seqno| accounting.account(&UdpHeader { streamid: 0, measured: true, seqno: *seqno, timestamp: timestamp() }, &mut update))
+            .collect();
+        (update, accounted)
+    }
+
+    #[test]
+    fn udp_accounting_in_order() {
+        let (update, accounted) = account(&[0, 1, 2, 3]);
+        assert_eq!((update.pktlost, update.pktoutoforder, update.pktduplicate), (0, 0, 0));
+        assert!(accounted.iter().all(|accounted| *accounted));
+    }
+
+    #[test]
+    fn udp_accounting_gap() {
+        let (update, _) = account(&[0, 1, 4, 5]);
+        assert_eq!((update.pktlost, update.pktoutoforder), (2, 0));
+    }
+
+    #[test]
+    fn udp_accounting_late_in_window() {
+        // The late datagrams are no longer lost
+        let (update, accounted) = account(&[0, 3, 1, 2, 4]);
+        assert_eq!((update.pktlost, update.pktoutoforder, update.pktduplicate), (0, 2, 0));
+        assert!(accounted.iter().all(|accounted| *accounted));
+    }
+
+    #[test]
+    fn udp_accounting_late_beyond_window() {
+        // Too late to detect the duplicates: counted as out of order
+        let (update, accounted) = account(&[0, SEQ_WINDOW + 1, 1, 1]);
+        assert_eq!((update.pktlost, update.pktoutoforder, update.pktduplicate), (SEQ_WINDOW - 2, 2, 0));
+        assert!(accounted.iter().all(|accounted| *accounted));
+    }
+
+    #[test]
+    fn udp_accounting_duplicates() {
+        let (update, accounted) = account(&[0, 1, 1, 2, 0]);
+        assert_eq!((update.pktlost, update.pktoutoforder, update.pktduplicate), (0, 0, 2));
+        assert_eq!(accounted, vec!(true, true, false, true, false));
+    }
+
+    #[test]
+    fn udp_accounting_window_slot_reused() {
+        // The slot of 1 is reused by 1 + SEQ_WINDOW, which is late but not a duplicate
+        let (update, accounted) = account(&[0, 1, SEQ_WINDOW + 2, SEQ_WINDOW + 1]);
+        assert_eq!((update.pktlost, update.pktoutoforder, update.pktduplicate), (SEQ_WINDOW - 1, 1, 0));
+        assert!(accounted.iter().all(|accounted| *accounted));
+    }
+
+    #[test]
+    fn udp_accounting_after_reset() {
+        // The loss of 1 was accounted before the counters were reset after the omitted period
+        let mut accounting = UdpAccounting::new();
+        let mut update = Update::default();
+        for seqno in [0, 2] {
+            accounting.account(&UdpHeader { streamid: 0, measured: true, seqno, timestamp: timestamp() }, &mut update);
+        }
+        let mut update = Update::default();
+        assert!(accounting.account(&UdpHeader { streamid: 0, measured: true, seqno: 1, timestamp: timestamp() }, &mut update));
+        assert_eq!((update.pktlost, update.pktoutoforder), (0, 1));
+    }
+
+    #[test]
+    fn udp_accounting_last_seqno() {
+        let (update, accounted) = account(&[0, u64::MAX, 1]);
+        assert_eq!(accounted, vec!(true, false, true));
+        assert_eq!(update.pktlost, 0);
+    }
+
+    #[test]
+    fn rate_limiter_unlimited() {
+        let mut limiter = RateLimiter::new(0, 0);
+        limiter.consume(1000000);
+        assert_eq!(limiter.delay(), None);
+    }
+
+    #[test]
+    fn rate_limiter_delay() {
+        // 1 MByte/sec: 1000 bytes are credited every millisecond
+        let mut limiter = RateLimiter::new(8000000, 10000);
+        let start = limiter.last_refill;
+        assert_eq!(limiter.delay_at(start), None);
+        limiter.consume(1000);
+        let delay = limiter.delay_at(start).unwrap();
+        assert!((delay.as_secs_f64() - 0.001).abs() < 1e-9);
+        assert_eq!(limiter.delay_at(start + Duration::from_millis(1)), None);
+    }
+
+    #[test]
+    fn rate_limiter_burst() {
+        // The tokens credited while idle are capped to the burst size
+        let mut limiter = RateLimiter::new(8000000, 10000);
+        let start = limiter.last_refill + Duration::from_secs(10);
+        assert_eq!(limiter.delay_at(start), None);
+        limiter.consume(10000);
+        assert_eq!(limiter.delay_at(start), None);
+        limiter.consume(1000);
+        let delay = limiter.delay_at(start).unwrap();
+        assert!((delay.as_secs_f64() - 0.001).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rate_limiter_accuracy() {
+        // Simulate a sender waiting for the limiter during 10s
+        for (bandwidth, len) in [(10000000, 100000), (10000000, 1400), (5000000000, 100000), (5000000000, 8000)] {
+            let burst = bandwidth / 8 / 100;
+            let mut limiter = RateLimiter::new(bandwidth, burst);
+            let start = limiter.last_refill;
+            let end = start + Duration::from_secs(10);
+            let mut now = start;
+            let mut bytes = 0;
+            while now < end {
+                match limiter.delay_at(now) {
+                    // The clock always moves on, as it does while sleeping
+                    Some(delay) => now += std::cmp::max(delay, Duration::from_nanos(1)),
+                    None => {
+                        limiter.consume(len);
+                        bytes += len;
+                    },
+                }
+            }
+            let expected = 10.0 * bandwidth as f64 / 8.0;
+            let error = (bytes as f64 - expected).abs() / expected;
+            assert!(error < 0.01, "{} bits/sec with {} bytes buffers: {} bytes sent", bandwidth, len, bytes);
+        }
+    }
+
+    #[test]
+    fn udp_loss_percent() {
+        let update = |pktcount, pktlost| Update { pktcount, pktlost, ..Default::default() };
+        assert_eq!(update(0, 0).get_loss_percent(), 0.0);
+        assert!(update(999, 1).get_loss_percent() <= 0.1);
+        assert!(update(998, 2).get_loss_percent() > 0.1);
+    }
+
+    #[test]
+    fn peer_timeout_pacing() {
+        let config = TestConfig::default();
+        assert_eq!(peer_timeout(&config), PEER_TIMEOUT);
+        // 10ms bursts
+        let config = TestConfig { bandwidth: Some(40_000), ..Default::default() };
+        assert_eq!(pacing_gap(&config), Duration::from_millis(10));
+        // A whole 100KB buffer sent at once every 20s
+        let config = TestConfig { bandwidth: Some(40_000), burst: Some(100_000), ..Default::default() };
+        assert_eq!(pacing_gap(&config), Duration::from_secs(20));
+        assert_eq!(peer_timeout(&config), PEER_TIMEOUT + Duration::from_secs(80));
+    }
+
+    #[test]
+    fn interval_timer_count_reached() {
+        let config = TestConfig { omit: 1, bytes: Some(1000), packets: Some(10), ..Default::default() };
+        let mut timer = IntervalTimer::new(&config);
+        // The counts do not include the omitted period
+        assert!(!timer.count_reached(1000, 10));
+        timer.end_omit(&mut Update::default(), &mut |_| {});
+        assert!(!timer.count_reached(999, 9));
+        assert!(timer.count_reached(1000, 0));
+        assert!(timer.count_reached(0, 10));
+    }
+
+    #[test]
+    fn interval_timer_is_due() {
+        let config = TestConfig { time: 2, omit: 2, ..Default::default() };
+        let secs = Duration::from_secs;
+
+        // The sender ends the omitted period on its clock
+        let timer = IntervalTimer::new(&config);
+        assert!(!timer.is_due(secs(1) - Duration::from_millis(1)));
+        assert!(timer.is_due(secs(1)));
+        assert!(timer.is_omit_over(secs(2)));
+
+        // The receiver waits for the sender to end the omitted period
+        let mut timer = IntervalTimer::receiver(&config);
+        assert!(timer.is_due(secs(1)));
+        let mut update = Update { elapsed: secs(1), ..Default::default() };
+        timer.tick(&mut update, &mut |_| {});
+        assert!(!timer.is_due(secs(5)));
+        assert!(!timer.is_over(secs(3)));
+        assert!(timer.is_over(secs(4)));
+
+        // The interval ending after the test duration is left to finish()
+        timer.end_omit(&mut update, &mut |_| {});
+        assert!(!timer.is_due(secs(1) - Duration::from_millis(1)));
+        update.elapsed = secs(2);
+        assert!(timer.is_due(update.elapsed));
+        timer.tick(&mut update, &mut |_| {});
+        assert!(!timer.is_due(secs(3)));
+    }
+
+    #[test]
+    fn interval_timer_finish() {
+        let config = TestConfig::default();
+        let mut timer = IntervalTimer::new(&config);
+        let mut reports = vec!();
+        let mut update = Update { elapsed: Duration::from_millis(50), pktcount: 1, ..Default::default() };
+
+        // The last interval is too short to be meaningful
+        timer.finish(&mut update, &mut |update| reports.push(update.clone()));
+        assert!(reports.is_empty());
+
+        update.elapsed = Duration::from_millis(200);
+        timer.finish(&mut update, &mut |update| reports.push(update.clone()));
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].interval.as_ref().unwrap().elapsed, Duration::from_millis(200));
+
+        // Nothing was received since the last report
+        update.elapsed = Duration::from_millis(400);
+        timer.finish(&mut update, &mut |update| reports.push(update.clone()));
+        assert_eq!(reports.len(), 1);
+    }
+
+    /// Run a sender ignoring the bandwidth the receiver limits the stream to
+    fn run_udp(bandwidth: u64, max_bandwidth: u64) -> Result<Update> {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.connect(receiver.local_addr().unwrap()).unwrap();
+        receiver.connect(sender.local_addr().unwrap()).unwrap();
+        let config = TestConfig { udp: true, time: 2, bandwidth: Some(bandwidth), ..Default::default() };
+        let send_config = config.clone();
+        let thread = std::thread::spawn(move || udp_send(&send_config, 0, sender, |_| {}));
+        let (_, reports) = std::sync::mpsc::channel();
+        let result = udp_recv(&config, max_bandwidth, &reports, 0, receiver, |_| {});
+        thread.join().unwrap().unwrap();
+        result
+    }
+
+    #[test]
+    fn udp_recv_max_bandwidth() {
+        let result = run_udp(10_000_000, 10_000_000).unwrap();
+        assert!(result.get_througtput().abs_diff(10_000_000) < 500_000, "{:?}", result);
+        assert!(run_udp(20_000_000, 10_000_000).is_err());
+    }
+
+    #[test]
+    fn tcp_recv_max_bandwidth() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let sender = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (receiver, _) = listener.accept().unwrap();
+        let config = TestConfig { time: 1, ..Default::default() };
+        let send_config = config.clone();
+        let thread = std::thread::spawn(move || tcp_send(&send_config, sender, |_| {}));
+        // The unlimited sender is throttled by the TCP window
+        let (_, reports) = std::sync::mpsc::channel();
+        let result = tcp_recv(&config, 50_000_000, &reports, receiver, |_| {}).unwrap();
+        thread.join().unwrap().unwrap();
+        assert!(result.get_througtput().abs_diff(50_000_000) < 2_500_000, "{:?}", result);
+    }
+}
@@ -1,71 +1,433 @@
 use eyre::{eyre, Result, WrapErr};
-use std::net::{TcpListener, TcpStream, SocketAddr, IpAddr, Ipv6Addr};
+use std::net::{TcpListener, TcpStream, UdpSocket, SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr, Shutdown};
+use std::thread::JoinHandle;
+use socket2::{Socket, SockRef};
 use std::sync::{
     Arc,
     RwLock,
+    Mutex,
+    Condvar,
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    mpsc::{channel, Sender, Receiver},
 };
-use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
 use crate::{
-    message::{Message, MessageIO},
-    args::{ArgsClient, ArgsServer},
+    aggregator::StreamReporter,
+    message::{self, Message, MessageIO, StreamReport},
+    args::ArgsServer,
+    config::TestConfig,
+    daemon,
     pktgenerator,
+    signal::TerminationSignals,
+    socket::{self, udp_bind, Family},
 };
 
+/// speednet server accepting speedtests from speednet clients
 #[derive(Default, Clone)]
 pub struct Server {
     inner: Arc<RwLock<ServerInner>>,
     args: ArgsServer,
+    stopped: Arc<AtomicBool>,
+    /// Number of client connections being handled
+    connections: Arc<AtomicUsize>,
+    /// Sink of the server logs, the logs are dropped if not set
+    logger: Option<Logger>,
+}
+
+/// Sink of the server logs, called with one line at a time
+type Logger = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// Handle on a started server
+pub struct ServerHandle {
+    server: Server,
+    listen_addr: SocketAddr,
+    tcp_thread: JoinHandle<Result<()>>,
+    udp_thread: JoinHandle<()>,
 }
 
 #[derive(Default)]
 struct ServerInner {
-    next_testid: u32,
-    speedtests: HashMap<u32, Speedtest>,
+    speedtests: HashMap<u64, Speedtest>,
+    listen_addr: Option<SocketAddr>,
+    /// State of the single test accepted in one-off mode
+    one_off_state: Option<TestState>,
 }
 
+/// Maximum time to wait for the client to start the test
+/// once the test is created
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum time, after the test duration, to wait for all
+/// the streams to be done before aborting the test
+const END_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum time to wait for the running tests to be aborted on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Period at which the control connection checks if the test was aborted
+const ABORT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Interval between the checks of the ClientStreamHello retransmitted
+/// on an UDP data stream waiting for the test start
+const UDP_START_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Maximum time to send the rejection to a connection over the limit
+const REJECT_TIMEOUT: Duration = Duration::from_millis(100);
+
 struct Speedtest {
-    config: ArgsClient,
+    config: TestConfig,
+    /// Address of the client, which shares its maximum bandwidth between its tests
+    peer: IpAddr,
+    reports: Sender<StreamReport>,
+    status: Arc<TestStatus>,
+    /// Data streams attached to the test
+    streams: HashSet<u32>,
+    /// Reports of the client sending the upload streams,
+    /// forwarded from the control connection to the streams
+    senders: HashMap<u32, Sender<StreamReport>>,
+    /// Control connection, shut down for reading to abort the test
+    control: Socket,
+    /// Data sockets, shut down to abort the test
+    sockets: Vec<Socket>,
 }
 
 impl Speedtest {
-    fn new(config: ArgsClient) -> Self {
+    fn new(config: TestConfig, peer: IpAddr, reports: Sender<StreamReport>, status: Arc<TestStatus>, control: Socket) -> Self {
         Self {
             config,
+            peer,
+            reports,
+            status,
+            streams: HashSet::new(),
+            senders: HashMap::new(),
+            control,
+            sockets: vec!(),
+        }
+    }
+
+    /// Return the total bandwidth of the test streams, 0 if unlimited
+    fn bandwidth(&self) -> u64 {
+        self.config.get_bandwidth().saturating_mul(self.config.get_streams() as u64)
+    }
+
+    /// Abort the test by shutting down all its sockets.
+    ///
+    /// The blocked reads and writes return and the control connection
+    /// informs the client.
+    fn abort(&self, reason: &str) {
+        self.status.abort(reason);
+        let _ = self.control.shutdown(Shutdown::Read);
+        for socket in &self.sockets {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Data stream attached to a speedtest
+struct AttachedStream {
+    config: TestConfig,
+    reporter: StreamReporter,
+    status: Arc<TestStatus>,
+    /// Reports of the client sending the stream
+    sender: Receiver<StreamReport>,
+}
+
+/// Count a client connection being handled until it is dropped
+struct ConnectionGuard {
+    connections: Arc<AtomicUsize>,
+}
+
+impl ConnectionGuard {
+    fn new(connections: &Arc<AtomicUsize>) -> Self {
+        connections.fetch_add(1, Ordering::Relaxed);
+        Self {
+            connections: connections.clone(),
         }
     }
 }
 
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Lifecycle of a speedtest on the server
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+enum TestState {
+    /// ServerHello was sent to the client
+    #[default]
+    Created,
+    /// Data streams are attaching to the test
+    Connecting,
+    /// ClientStartTest was received: all the streams are running
+    Running,
+    /// All the streams are done
+    Finished,
+    /// The control connection was closed or the test timed out
+    Aborted,
+}
+
+/// State of a speedtest shared between the control connection and the streams.
+///
+/// It is also used to start all the streams of a speedtest at the same time.
+#[derive(Default)]
+struct TestStatus {
+    state: Mutex<TestState>,
+    condvar: Condvar,
+    /// Reason of the abort reported to the client
+    reason: Mutex<String>,
+}
+
+impl TestStatus {
+    fn get(&self) -> TestState {
+        *self.state.lock().unwrap()
+    }
+
+    fn set(&self, state: TestState) {
+        *self.state.lock().unwrap() = state;
+        self.condvar.notify_all();
+    }
+
+    /// Abort the test with the specified reason, unless it is already over
+    fn abort(&self, reason: &str) {
+        let mut state = self.state.lock().unwrap();
+        if *state == TestState::Finished || *state == TestState::Aborted {
+            return;
+        }
+        *state = TestState::Aborted;
+        *self.reason.lock().unwrap() = reason.to_string();
+        self.condvar.notify_all();
+    }
+
+    fn reason(&self) -> String {
+        self.reason.lock().unwrap().clone()
+    }
+
+    /// Wait for the test to leave the Created and Connecting states
+    /// and return the new state. The current state is returned on timeout.
+    fn wait_start(&self, timeout: Duration) -> TestState {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self.condvar.wait_timeout_while(state, timeout, |state| {
+            *state == TestState::Created || *state == TestState::Connecting
+        }).unwrap();
+        *state
+    }
+}
+
+/// Return a random test ID, so that a client can not guess
+/// the ID of another client test.
+fn random_testid() -> Result<u64> {
+    let mut buff = [0u8; 8];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut file| file.read_exact(&mut buff))
+        .wrap_err("Failed to read /dev/urandom")?;
+    Ok(u64::from_ne_bytes(buff))
+}
+
 impl Server {
     pub fn new(args: ArgsServer) -> Result<Self> {
         Ok(Self {
             inner: Arc::default(),
             args,
+            stopped: Arc::default(),
+            connections: Arc::default(),
+            logger: None,
         })
     }
 
+    /// Send the server logs to the specified sink
+    pub fn with_logger<F: Fn(&str) + Send + Sync + 'static>(mut self, logger: F) -> Self {
+        self.logger = Some(Arc::new(logger));
+        self
+    }
+
+    fn log(&self, args: std::fmt::Arguments) {
+        if let Some(logger) = &self.logger {
+            logger(&args.to_string());
+        }
+    }
+
+    /// Run the server until it fails or until SIGINT or SIGTERM is received.
+    ///
+    /// The logs are printed on the standard output, unless a logger is set.
+    /// The server detaches from the terminal if daemon mode is enabled.
+    /// The termination signals are blocked in the calling thread and
+    /// handled by a dedicated thread.
     pub fn run(&self) -> Result<()> {
-        let ip_addr = match &self.args.bind {
-            Some(hostname) => hostname.parse::<IpAddr>().wrap_err("Invalid hostname")?,
-            None => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        // The paths must be resolved before the daemon changes its working directory
+        let pidfile = self.args.pidfile.as_deref().map(daemon::absolute).transpose()?;
+        let logfile = self.args.logfile.as_deref().map(daemon::absolute).transpose()?;
+
+        // The listeners are bound before detaching so that errors are reported
+        // on the terminal, but no thread may be spawned before forking.
+        let listeners = self.bind()?;
+        if self.args.daemon {
+            daemon::daemonize(pidfile.as_deref(), logfile.as_deref())?;
+        }
+        else {
+            if let Some(logfile) = &logfile {
+                daemon::redirect_output(logfile)?;
+            }
+            if let Some(pidfile) = &pidfile {
+                daemon::write_pidfile(pidfile)?;
+            }
+        }
+
+        let me = match &self.logger {
+            Some(_) => self.clone(),
+            None => self.clone().with_logger(|line| println!("{}", line)),
+        };
+        let signals = TerminationSignals::block()?;
+        let handle = me.spawn(listeners);
+
+        std::thread::spawn(move || {
+            match signals.wait() {
+                Ok(signal) => me.log(format_args!("Received {}: shutting down", signal)),
+                Err(e) => me.log(format_args!("Signal handling error: {:?}", e)),
+            }
+            me.shutdown();
+        });
+
+        let result = handle.wait();
+        if let Some(pidfile) = &pidfile {
+            let _ = std::fs::remove_file(pidfile);
+        }
+        result
+    }
+
+    /// Start the server in background threads and return a handle to stop it
+    pub fn start(&self) -> Result<ServerHandle> {
+        let listeners = self.bind()?;
+        Ok(self.spawn(listeners))
+    }
+
+    /// Bind the TCP and UDP listeners
+    fn bind(&self) -> Result<(TcpListener, UdpSocket)> {
+        let family = Family::new(self.args.ipv4, self.args.ipv6);
+        let bind_addr = match &self.args.bind {
+            Some(hostname) => socket::resolve(hostname, self.args.port, family)?[0],
+            None if family == Family::V4 => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), self.args.port),
+            None => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), self.args.port),
         };
-        let listen_addr = SocketAddr::new(ip_addr, self.args.port);
+        let listener = socket::tcp_listen(bind_addr, self.args.ipv6)?;
 
-        //let (tx, rx) = std::sync::mpsc::channel();
+        // The UDP listener uses the same port than the TCP listener
+        // which may have been allocated by the system
+        let listen_addr = listener.local_addr()
+            .wrap_err("Failed to get listener address")?;
+        let udp_listener = udp_bind(listen_addr, self.args.ipv6)
+            .wrap_err("Failed to bind UDP socket")?;
 
-        println!("speednet server listening on {:?}", listen_addr);
-        let listener = TcpListener::bind(listen_addr)?;
+        Ok((listener, udp_listener))
+    }
+
+    /// Spawn the listeners threads
+    fn spawn(&self, listeners: (TcpListener, UdpSocket)) -> ServerHandle {
+        let (listener, udp_listener) = listeners;
+        let listen_addr = listener.local_addr()
+            .expect("TCP listener has no address");
+        self.log(format_args!("speednet server listening on {:?}", listen_addr));
+        self.inner.write().unwrap().listen_addr = Some(listen_addr);
+
+        let me = self.clone();
+        let udp_thread = std::thread::spawn(move || {
+            if let Err(e) = me.udp_listen(udp_listener, listen_addr) {
+                me.log(format_args!("UDP listener error: {:?}", e));
+            }
+        });
+
+        let me = self.clone();
+        let tcp_thread = std::thread::spawn(move || me.tcp_listen(listener));
+
+        ServerHandle {
+            server: self.clone(),
+            listen_addr,
+            tcp_thread,
+            udp_thread,
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Stop accepting new clients and abort the running tests.
+    ///
+    /// The clients are informed over the control connection.
+    pub fn shutdown(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+
+        let server = self.inner.read().unwrap();
+        for speedtest in server.speedtests.values() {
+            speedtest.abort("Server is shutting down");
+        }
+
+        // Wake up the listeners blocked waiting for a new client
+        let listen_addr = match server.listen_addr {
+            Some(listen_addr) => listen_addr,
+            None => {return;},
+        };
+        let wakeup_ip = match listen_addr.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ip => ip,
+        };
+        let wakeup_addr = SocketAddr::new(wakeup_ip, listen_addr.port());
+        let _ = TcpStream::connect(wakeup_addr);
+        let bind_addr = match wakeup_addr {
+            SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+        };
+        if let Ok(socket) = UdpSocket::bind(bind_addr) {
+            let _ = socket.send_to(&[], wakeup_addr);
+        }
+    }
+
+    /// Wait for the running tests to be removed, up to the specified timeout
+    fn wait_speedtests(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        while !self.inner.read().unwrap().speedtests.is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Return true if the maximum number of client connections,
+    /// TCP connections and UDP data streams, is reached
+    fn is_busy(&self) -> bool {
+        // Each test uses one control connection and one connection per stream
+        let max_connections = self.args.max_tests * (self.args.max_streams as usize + 1);
+        self.connections.load(Ordering::Relaxed) >= max_connections
+    }
+
+    /// Accept the control and data TCP connections
+    fn tcp_listen(&self, listener: TcpListener) -> Result<()> {
         for stream in listener.incoming() {
+            if self.is_stopped() {
+                break;
+            }
             let me = self.clone();
-            let stream = match stream {
+            let mut stream = match stream {
                 Ok(stream) => stream,
                 Err(e) => {
-                    println!("Connection error: {:?}", e);
+                    self.log(format_args!("Connection error: {:?}", e));
                     continue;
                 }
             };
+            if self.is_busy() {
+                self.log(format_args!("Too many connections: closing connection from {:?}", stream.peer_addr()));
+                // The client is not read: do not block the listener on a full send buffer
+                let _ = stream.set_write_timeout(Some(REJECT_TIMEOUT));
+                let _ = stream.sendmsg(&Message::ServerReject("Server is busy: too many connections".to_string()));
+                continue;
+            }
+            let guard = ConnectionGuard::new(&self.connections);
             std::thread::spawn(move || {
+                let _guard = guard;
                 if let Err(e) = me.server_handle_new_client(stream) {
-                    println!("Client error: {:?}", e);
+                    me.log(format_args!("Client error: {:?}", e));
                 }
             });
         }
@@ -74,93 +436,529 @@ impl Server {
     }
 
     fn server_handle_new_client(&self, mut stream: TcpStream) -> Result<()> {
-        let msg = stream.recvmsg()
-            .wrap_err("Failed to read client hello message")?;
+        // Do not keep a thread for a client which never says hello
+        stream.set_read_timeout(Some(START_TIMEOUT))
+            .wrap_err("Failed to set read timeout")?;
+        let msg = match stream.recvmsg() {
+            Ok(msg) => msg,
+            Err(e) if message::is_version_mismatch(&e) => {
+                // The client detects the mismatch from the version of the reply
+                let _ = stream.sendmsg(&Message::ServerReject(e.to_string()));
+                return Err(e.wrap_err("Rejected client"));
+            },
+            Err(e) => {return Err(e.wrap_err("Failed to read client hello message"));},
+        };
+        stream.set_read_timeout(None)
+            .wrap_err("Failed to clear read timeout")?;
 
         match msg {
-            Message::ClientHello(config) => self.server_handle_client_hello(stream, config),
-            Message::ClientStreamHello(testid, _streamid) => self.server_handle_client_start_stream(stream, testid),
+            Message::ClientHello { capabilities, config } => self.server_handle_client_hello(stream, capabilities, config),
+            Message::ClientStreamHello(testid, streamid) => self.server_handle_client_start_stream(stream, testid, streamid),
             _ => Err(eyre!("Received an unexpected message: {:?}", msg)),
         }
     }
 
-    pub fn server_handle_tcp_download(&self, stream: TcpStream, config: ArgsClient) -> Result<()> {
-        println!("Handle TCP Download");
-        let result = pktgenerator::tcp_send(&config, stream, |update| {
-            println!("Elapsed: {}", update.elapsed.as_secs());
-            println!("pktsent: {}", update.pktcount);
-            println!("expected: {}", update.pktcount_expected);
-            println!("");
-        })?;
-        println!("Handle TCP Download done");
-        println!("Elapsed: {}", result.elapsed.as_secs());
-        println!("Pkt Sent: {}", result.pktcount);
+    fn server_handle_tcp_download(&self, stream: TcpStream, config: TestConfig, reporter: &mut StreamReporter) -> Result<()> {
+        self.log(format_args!("Handle TCP Download"));
+        let result = pktgenerator::tcp_send(&config, stream, |update| reporter.update(update))?;
+        reporter.done(&result);
+        self.log(format_args!("Handle TCP Download done"));
+        self.log(format_args!("Elapsed: {}", result.elapsed.as_secs()));
+        self.log(format_args!("Pkt Sent: {}", result.pktcount));
         Ok(())
     }
 
-    fn server_handle_tcp_upload(&self, stream: TcpStream, config: ArgsClient) -> Result<()> {
-        println!("Handle TCP Upload");
-        let result = pktgenerator::tcp_recv(&config, stream, |update| {
-            println!("Elapsed: {}", update.elapsed.as_secs());
-            println!("pktrecv: {}", update.pktcount);
-            println!("");
-        })?;
+    fn server_handle_tcp_upload(&self, stream: TcpStream, config: TestConfig, sender: &Receiver<StreamReport>, reporter: &mut StreamReporter) -> Result<()> {
+        self.log(format_args!("Handle TCP Upload"));
+        let result = pktgenerator::tcp_recv(&config, self.max_stream_bandwidth(&config), sender, stream, |update| reporter.update(update))?;
+        reporter.done(&result);
 
-        println!("Handle TCP Upload done");
-        println!("Elapsed: {}", result.elapsed.as_secs());
-        println!("Pkt Recv: {}", result.pktcount);
+        self.log(format_args!("Handle TCP Upload done"));
+        self.log(format_args!("Elapsed: {}", result.elapsed.as_secs()));
+        self.log(format_args!("Pkt Recv: {}", result.pktcount));
         Ok(())
     }
 
-    fn server_handle_client_start_stream(&self, stream: TcpStream, testid: u32) -> Result<()> {
-        println!("Test id: {}", testid);
-
+    /// Attach a data stream to the specified speedtest.
+    ///
+    /// Return the speedtest configuration and status,
+    /// a reporter and the client reports for the specified stream.
+    fn attach_stream(&self, testid: u64, streamid: u32) -> Result<AttachedStream> {
         let mut server = self.inner.write().unwrap();
         let speedtest = match server.speedtests.get_mut(&testid) {
             Some(speedtest) => speedtest,
             None => {
-                return Err(eyre!("Unknown testid {}", testid));
+                return Err(eyre!("Unknown testid {:x}", testid));
             },
         };
 
-        let config = speedtest.config.clone();
-        drop(server);
+        let state = speedtest.status.get();
+        if state != TestState::Created && state != TestState::Connecting {
+            return Err(eyre!("Test {:x} does not accept streams in {:?} state", testid, state));
+        }
+        if streamid >= speedtest.config.get_streams() || !speedtest.streams.insert(streamid) {
+            return Err(eyre!("Test {:x} does not accept stream {}", testid, streamid));
+        }
+        speedtest.status.set(TestState::Connecting);
+        let reporter = StreamReporter::new(speedtest.reports.clone(), streamid, speedtest.config.is_download(streamid));
+        let (sender_tx, sender) = channel();
+        speedtest.senders.insert(streamid, sender_tx);
+
+        Ok(AttachedStream {
+            config: speedtest.config.clone(),
+            reporter,
+            status: speedtest.status.clone(),
+            sender,
+        })
+    }
+
+    /// Abort the specified speedtest, if it is still running
+    fn abort_test(&self, testid: u64, reason: &str) {
+        if let Some(speedtest) = self.inner.read().unwrap().speedtests.get(&testid) {
+            speedtest.abort(reason);
+        }
+    }
 
-        match config.revert {
-            true => self.server_handle_tcp_download(stream, config)?,
-            false => self.server_handle_tcp_upload(stream, config)?,
+    /// Register a data socket of the specified speedtest,
+    /// so that it is shut down if the test is aborted.
+    fn add_socket(&self, testid: u64, socket: SockRef) -> Result<()> {
+        let socket = socket.try_clone()
+            .wrap_err("Failed to clone socket")?;
+        let mut server = self.inner.write().unwrap();
+        let speedtest = server.speedtests.get_mut(&testid)
+            .ok_or(eyre!("Test {:x} is over", testid))?;
+        if speedtest.status.get() == TestState::Aborted {
+            return Err(eyre!("Test {:x} is aborted", testid));
+        }
+        speedtest.sockets.push(socket);
+        Ok(())
+    }
+
+    fn server_handle_client_start_stream(&self, mut stream: TcpStream, testid: u64, streamid: u32) -> Result<()> {
+        self.log(format_args!("Test id: {:x}", testid));
+
+        let AttachedStream { config, mut reporter, status, sender } = self.attach_stream(testid, streamid)?;
+        self.add_socket(testid, SockRef::from(&stream))?;
+        socket::set_data_options(SockRef::from(&stream), &config)?;
+        stream.sendmsg(&Message::ServerStreamHello)
+            .wrap_err("Failed to send server stream hello")?;
+        let state = status.wait_start(START_TIMEOUT);
+        if state != TestState::Running {
+            return Err(eyre!("Test {:x} was not started: {:?}", testid, state));
+        }
+
+        let result = match config.is_download(streamid) {
+            true => self.server_handle_tcp_download(stream, config, &mut reporter),
+            false => self.server_handle_tcp_upload(stream, config, &sender, &mut reporter),
         };
+        // The test is aborted before the reporter reports the stream as done
+        if let Err(e) = &result {
+            self.abort_test(testid, &format!("Stream {} failed: {:#}", streamid, e));
+        }
+        drop(reporter);
+
+        result
+    }
+
+    /// Wait for ClientStreamHello messages on the UDP server socket.
+    ///
+    /// A dedicated UDP socket, bound to the same address and connected to
+    /// the client, is created for each new UDP data stream so the datagrams
+    /// sent by the server go through the same NAT mapping than the client ones.
+    fn udp_listen(&self, socket: UdpSocket, listen_addr: SocketAddr) -> Result<()> {
+        let mut buff = vec!(0; 65536);
+        loop {
+            let (len, peer) = socket.recv_from(&mut buff)
+                .wrap_err("Failed to receive UDP datagram")?;
+            if self.is_stopped() {
+                return Ok(());
+            }
 
+            let (testid, streamid) = match Message::decode(&buff[..len]) {
+                Ok(Message::ClientStreamHello(testid, streamid)) => (testid, streamid),
+                Ok(msg) => {
+                    self.log(format_args!("Received an unexpected UDP message from {}: {:?}", peer, msg));
+                    continue;
+                },
+                Err(_) => {
+                    // Late datagram from a terminated UDP stream
+                    continue;
+                },
+            };
+
+            if self.is_busy() {
+                self.log(format_args!("Too many connections: ignoring UDP stream from {}", peer));
+                let msg = Message::ServerReject("Server is busy: too many connections".to_string());
+                if let Ok(buff) = msg.encode() {
+                    let _ = socket.send_to(&buff, peer);
+                }
+                continue;
+            }
+            // The ClientStreamHello retransmitted before the stream socket
+            // is connected are rejected here, without spawning a thread
+            let stream = match self.attach_stream(testid, streamid) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    self.log(format_args!("UDP stream error: {:?}", e));
+                    continue;
+                },
+            };
+            let guard = ConnectionGuard::new(&self.connections);
+            let me = self.clone();
+            std::thread::spawn(move || {
+                let _guard = guard;
+                if let Err(e) = me.server_handle_udp_stream(listen_addr, peer, testid, streamid, stream) {
+                    me.log(format_args!("UDP stream error: {:?}", e));
+                }
+            });
+        }
+    }
+
+    fn server_handle_udp_stream(&self, listen_addr: SocketAddr, peer: SocketAddr, testid: u64, streamid: u32,
+            AttachedStream { config, mut reporter, status, sender }: AttachedStream) -> Result<()> {
+        self.log(format_args!("Test id: {:x}", testid));
+
+        let mut socket = udp_bind(listen_addr, self.args.ipv6)
+            .wrap_err("Failed to bind UDP stream socket")?;
+        socket::set_data_options(SockRef::from(&socket), &config)?;
+        socket.connect(peer)
+            .wrap_err("Failed to connect UDP stream socket")?;
+        self.add_socket(testid, SockRef::from(&socket))?;
+        socket.sendmsg(&Message::ServerStreamHello)
+            .wrap_err("Failed to send server stream hello")?;
+        udp_wait_start(&mut socket, &status)
+            .wrap_err_with(|| format!("Test {:x} was not started", testid))?;
+
+        let result = match config.is_download(streamid) {
+            true => self.server_handle_udp_download(socket, config, streamid, &mut reporter),
+            false => self.server_handle_udp_upload(socket, config, streamid, &sender, &mut reporter),
+        };
+        // The test is aborted before the reporter reports the stream as done
+        if let Err(e) = &result {
+            self.abort_test(testid, &format!("Stream {} failed: {:#}", streamid, e));
+        }
+        drop(reporter);
+
+        result
+    }
+
+    fn server_handle_udp_download(&self, socket: UdpSocket, config: TestConfig, streamid: u32, reporter: &mut StreamReporter) -> Result<()> {
+        self.log(format_args!("Handle UDP Download"));
+        let result = pktgenerator::udp_send(&config, streamid, socket, |update| reporter.update(update))?;
+        reporter.done(&result);
+        self.log(format_args!("Handle UDP Download done"));
+        self.log(format_args!("Elapsed: {}", result.elapsed.as_secs()));
+        self.log(format_args!("Pkt Sent: {}", result.pktcount));
+        Ok(())
+    }
+
+    fn server_handle_udp_upload(&self, socket: UdpSocket, config: TestConfig, streamid: u32, sender: &Receiver<StreamReport>, reporter: &mut StreamReporter) -> Result<()> {
+        self.log(format_args!("Handle UDP Upload"));
+        let result = pktgenerator::udp_recv(&config, self.max_stream_bandwidth(&config), sender, streamid, socket, |update| reporter.update(update))?;
+        reporter.done(&result);
+
+        self.log(format_args!("Handle UDP Upload done"));
+        self.log(format_args!("Elapsed: {}", result.elapsed.as_secs()));
+        self.log(format_args!("Pkt Recv: {}", result.pktcount));
+        self.log(format_args!("Pkt Lost: {} ({:.3}%)", result.pktlost, result.get_loss_percent()));
+        self.log(format_args!("Pkt Out of order: {}", result.pktoutoforder));
+        self.log(format_args!("Pkt Duplicate: {}", result.pktduplicate));
+        self.log(format_args!("Jitter: {:?}", result.jitter));
         Ok(())
     }
 
-    fn server_handle_client_hello(&self, mut stream: TcpStream, config: ArgsClient) -> Result<()> {
-        println!("Client config: {:?}", config);
+    fn server_handle_client_hello(&self, mut stream: TcpStream, capabilities: Vec<String>, config: TestConfig) -> Result<()> {
+        self.log(format_args!("Client capabilities: {:?}", capabilities));
+        self.log(format_args!("Client config: {:?}", config));
+
+        let peer = stream.peer_addr()
+            .wrap_err("Failed to get client address")?.ip();
+        let mut config = match self.apply_limits(config) {
+            Ok(config) => config,
+            Err(e) => {
+                let _ = stream.sendmsg(&Message::ServerReject(e.to_string()));
+                return Err(e.wrap_err("Rejected client config"));
+            },
+        };
 
         // Create a new speedtest instance
+        let (reports_tx, reports_rx) = channel();
+        let status = Arc::new(TestStatus::default());
+        let control = Socket::from(stream.try_clone()
+            .wrap_err("Failed to clone control stream")?);
         let mut server = self.inner.write().unwrap();
-        let testid = server.next_testid;
-        let speedtest = Speedtest::new(config);
+        let reject = match self.is_stopped() {
+            true => Some("Server is shutting down".to_string()),
+            false if self.args.one_off && server.one_off_state.is_some() =>
+                Some("Server only accepts a single test".to_string()),
+            false if server.speedtests.len() >= self.args.max_tests =>
+                Some(format!("Server is busy: {} tests running", server.speedtests.len())),
+            false => self.limit_bandwidth(&server, peer, &mut config).err().map(|e| e.to_string()),
+        };
+        if let Some(reason) = reject {
+            drop(server);
+            let _ = stream.sendmsg(&Message::ServerReject(reason.clone()));
+            return Err(eyre!("Rejected client: {}", reason));
+        }
+        let mut testid = random_testid()?;
+        while server.speedtests.contains_key(&testid) {
+            testid = random_testid()?;
+        }
+        let speedtest = Speedtest::new(config.clone(), peer, reports_tx, status.clone(), control);
         server.speedtests.insert(testid, speedtest);
-        server.next_testid = testid + 1;
+        if self.args.one_off {
+            server.one_off_state = Some(TestState::Created);
+        }
         drop(server);
 
+        let result = self.run_speedtest(stream, testid, &config, &status, reports_rx);
+
+        // The speedtest is removed as soon as the control connection is closed
+        self.abort_test(testid, "Control connection closed");
+        self.log(format_args!("Test {:x} {:?}", testid, status.get()));
+        let mut server = self.inner.write().unwrap();
+        server.speedtests.remove(&testid);
+        if self.args.one_off {
+            server.one_off_state = Some(status.get());
+            drop(server);
+            self.shutdown();
+        }
+
+        result
+    }
+
+    /// Check the client configuration against the server limits.
+    ///
+    /// Return the configuration accepted by the server, where a test
+    /// without duration lasts at most the maximum test duration.
+    fn apply_limits(&self, mut config: TestConfig) -> Result<TestConfig> {
+        config.validate()?;
+        if let Some(congestion) = config.congestion.as_ref().filter(|_| config.revert || config.bidir) {
+            socket::check_congestion(congestion)?;
+        }
+        if config.get_streams() > self.args.max_streams {
+            return Err(eyre!("Too many streams {} (max {})", config.get_streams(), self.args.max_streams));
+        }
+        if config.time == 0 {
+            config.time = std::cmp::max(self.args.max_time.saturating_sub(config.omit), 1);
+        }
+        if config.get_duration() > self.args.max_time {
+            return Err(eyre!("Test duration {}s is too long (max {}s)", config.get_duration(), self.args.max_time));
+        }
+        Ok(config)
+    }
+
+    /// Restrict the bandwidth of each stream of a new test so that
+    /// all the running tests of the client IP address share the
+    /// maximum bandwidth per client.
+    ///
+    /// The server sends at the bandwidth configured in the ServerHello
+    /// and enforces it on the uploads, see max_stream_bandwidth().
+    fn limit_bandwidth(&self, server: &ServerInner, peer: IpAddr, config: &mut TestConfig) -> Result<()> {
+        let max_bandwidth = match self.args.max_bandwidth {
+            Some(max_bandwidth) => max_bandwidth,
+            None => {return Ok(());},
+        };
+        let used: u64 = server.speedtests.values()
+            .filter(|speedtest| speedtest.peer == peer)
+            .map(|speedtest| speedtest.bandwidth())
+            .sum();
+        let max_stream_bandwidth = max_bandwidth.saturating_sub(used) / config.get_streams() as u64;
+        if max_stream_bandwidth == 0 {
+            return Err(eyre!("Server is busy: maximum bandwidth used by the running tests of {}", peer));
+        }
+        if config.get_bandwidth() == 0 || config.get_bandwidth() > max_stream_bandwidth {
+            self.log(format_args!("Limit bandwidth to {} bits/sec per stream", max_stream_bandwidth));
+            config.bandwidth = Some(max_stream_bandwidth);
+        }
+        Ok(())
+    }
+
+    /// Return the maximum bandwidth the server receives a stream at,
+    /// 0 if unlimited.
+    ///
+    /// The TCP uploads are throttled by reading them at the stream bandwidth
+    /// and the UDP uploads faster than the stream bandwidth are aborted.
+    fn max_stream_bandwidth(&self, config: &TestConfig) -> u64 {
+        match self.args.max_bandwidth {
+            Some(_) => config.get_bandwidth(),
+            None => 0,
+        }
+    }
+
+    fn run_speedtest(&self, mut stream: TcpStream, testid: u64, config: &TestConfig, status: &TestStatus, reports_rx: Receiver<StreamReport>) -> Result<()> {
         // Reply with Server Hello
-        stream.sendmsg(&Message::ServerHello(testid))
+        let server_hello = Message::ServerHello {
+            testid,
+            capabilities: message::capabilities(),
+            config: config.clone(),
+        };
+        stream.sendmsg(&server_hello)
             .wrap_err("Failed to send server hello")?;
 
-        println!("Server hello sent");
-
+        self.log(format_args!("Server hello sent"));
 
         // Wait for ClientStartTest message
-        let msg = stream.recvmsg()
-            .wrap_err("Failed to receive 'ClientStartTest' message")?;
+        stream.set_read_timeout(Some(START_TIMEOUT))
+            .wrap_err("Failed to set control stream read timeout")?;
+        let msg = match stream.recvmsg() {
+            Ok(msg) => msg,
+            Err(_) if status.get() == TestState::Aborted => {
+                return abort_speedtest(&mut stream, testid, status);
+            },
+            Err(e) => {return Err(e.wrap_err("Failed to receive 'ClientStartTest' message"));},
+        };
         if msg != Message::ClientStartTest {
             return Err(eyre!("Receive unexpected message: {:?}", msg));
         }
+        status.set(TestState::Running);
+        let closed = self.spawn_control_reader(&stream, testid)?;
+
+        // Forward the streams reports to the client until all streams are done
+        let deadline = Instant::now() + Duration::from_secs(config.get_duration()) + END_TIMEOUT;
+        let mut done = 0;
+        while done < config.get_streams() {
+            if Instant::now() >= deadline {
+                return Err(eyre!("Test {:x} timed out", testid));
+            }
+            let report = reports_rx.recv_timeout(ABORT_POLL_INTERVAL);
+            // The reports of the aborted streams are not forwarded
+            if status.get() == TestState::Aborted {
+                return abort_speedtest(&mut stream, testid, status);
+            }
+            let report = match report {
+                Ok(report) => report,
+                Err(_) if closed.load(Ordering::Relaxed) => {
+                    return Err(eyre!("Client closed the control connection of test {:x}", testid));
+                },
+                Err(_) => {continue;},
+            };
+            if report.done {
+                done += 1;
+            }
+            stream.sendmsg(&Message::ServerTestUpdate(report))
+                .wrap_err("Failed to send test update")?;
+        }
+        status.set(TestState::Finished);
 
         Ok(())
     }
 
+    /// Read the client messages on the control connection of a running test,
+    /// forwarding the client reports to the upload streams.
+    ///
+    /// Return a flag set once the client closed the control connection.
+    fn spawn_control_reader(&self, stream: &TcpStream, testid: u64) -> Result<Arc<AtomicBool>> {
+        let mut stream = stream.try_clone()
+            .wrap_err("Failed to clone control stream")?;
+        stream.set_read_timeout(None)
+            .wrap_err("Failed to clear control stream read timeout")?;
+        let closed = Arc::new(AtomicBool::new(false));
+        let flag = closed.clone();
+        let me = self.clone();
+        std::thread::spawn(move || {
+            // The control connection is shut down for reading when the test is over
+            while let Ok(msg) = stream.recvmsg() {
+                let report = match msg {
+                    Message::ClientTestUpdate(report) => report,
+                    msg => {
+                        me.log(format_args!("Received an unexpected message: {:?}", msg));
+                        continue;
+                    },
+                };
+                let server = me.inner.read().unwrap();
+                if let Some(sender) = server.speedtests.get(&testid).and_then(|speedtest| speedtest.senders.get(&report.streamid)) {
+                    let _ = sender.send(report);
+                }
+            }
+            flag.store(true, Ordering::Relaxed);
+        });
+        Ok(closed)
+    }
+}
+
+impl ServerHandle {
+    /// Return the address the server is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listen_addr
+    }
+
+    /// Wait for the server to stop.
+    ///
+    /// Once the listeners exited, the aborted tests are given some time
+    /// to inform their clients.
+    ///
+    /// In one-off mode, an error is returned if the test did not complete.
+    pub fn wait(self) -> Result<()> {
+        let result = self.tcp_thread.join()
+            .map_err(|e| eyre!("Server thread returned an error: {:?}", e))?;
+        let _ = self.udp_thread.join();
+        self.server.wait_speedtests(SHUTDOWN_TIMEOUT);
+        result?;
+
+        if !self.server.args.one_off {
+            return Ok(());
+        }
+        match self.server.inner.read().unwrap().one_off_state {
+            Some(TestState::Finished) => Ok(()),
+            Some(state) => Err(eyre!("One-off test {:?}", state)),
+            None => Err(eyre!("Server stopped before running the one-off test")),
+        }
+    }
+
+    /// Stop the server, abort the running tests and wait for the server to exit
+    pub fn stop(self) -> Result<()> {
+        self.server.shutdown();
+        self.wait()
+    }
+}
+
+/// Inform the client that the test is aborted by the server
+fn abort_speedtest(stream: &mut TcpStream, testid: u64, status: &TestStatus) -> Result<()> {
+    let reason = status.reason();
+    stream.sendmsg(&Message::ServerAbort(reason.clone()))
+        .wrap_err("Failed to send server abort")?;
+    Err(eyre!("Test {:x} aborted: {}", testid, reason))
 }
 
+/// Wait for the test to be started on an UDP data stream.
+///
+/// The ClientStreamHello retransmitted by the client, when the
+/// ServerStreamHello is lost, are acknowledged until the test starts.
+/// The socket is only polled between the waits for the start, so that
+/// the sender starts as soon as the test does.
+fn udp_wait_start(socket: &mut UdpSocket, status: &TestStatus) -> Result<()> {
+    let mut buff = vec!(0; 65536);
+    let deadline = Instant::now() + START_TIMEOUT;
+
+    socket.set_nonblocking(true)
+        .wrap_err("Failed to set non-blocking mode")?;
+    loop {
+        match status.wait_start(UDP_START_POLL_INTERVAL) {
+            TestState::Running => {break;},
+            TestState::Created | TestState::Connecting => {},
+            state => {return Err(eyre!("Test is {:?}", state));},
+        }
+        if Instant::now() >= deadline {
+            return Err(eyre!("Timeout"));
+        }
+        let len = match socket.peek(&mut buff) {
+            Ok(len) => len,
+            Err(_) => {continue;},
+        };
+        match Message::decode(&buff[..len]) {
+            Ok(Message::ClientStreamHello(_, _)) => {
+                socket.recv(&mut buff)
+                    .wrap_err("Failed to read message")?;
+                // The client retransmits its ClientStreamHello until acknowledged
+                let _ = socket.sendmsg(&Message::ServerStreamHello);
+            },
+            // The client already started to send data
+            _ => {break;},
+        }
+    }
+    socket.set_nonblocking(false)
+        .wrap_err("Failed to clear non-blocking mode")?;
+
+    Ok(())
+}
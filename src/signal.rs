@@ -0,0 +1,45 @@
+/// Synchronous handling of the termination signals
+use eyre::{eyre, Result, WrapErr};
+use std::io;
+
+/// SIGINT and SIGTERM, blocked in all the threads and received with sigwait()
+pub struct TerminationSignals {
+    set: libc::sigset_t,
+}
+
+impl TerminationSignals {
+    /// Block SIGINT and SIGTERM in the calling thread.
+    ///
+    /// The threads spawned afterwards inherit the signal mask, so the
+    /// signals are only received by the thread calling wait().
+    pub fn block() -> Result<Self> {
+        let set = unsafe {
+            let mut set: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, libc::SIGINT);
+            libc::sigaddset(&mut set, libc::SIGTERM);
+            set
+        };
+        let rc = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+        if rc != 0 {
+            return Err(io::Error::from_raw_os_error(rc))
+                .wrap_err("Failed to block termination signals");
+        }
+        Ok(Self {set})
+    }
+
+    /// Wait for a termination signal and return its name
+    pub fn wait(&self) -> Result<&'static str> {
+        let mut signum = 0;
+        let rc = unsafe { libc::sigwait(&self.set, &mut signum) };
+        if rc != 0 {
+            return Err(io::Error::from_raw_os_error(rc))
+                .wrap_err("Failed to wait for termination signals");
+        }
+        match signum {
+            libc::SIGINT => Ok("SIGINT"),
+            libc::SIGTERM => Ok("SIGTERM"),
+            _ => Err(eyre!("Received unexpected signal {}", signum)),
+        }
+    }
+}
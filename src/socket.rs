@@ -0,0 +1,226 @@
+/// Socket creation and socket options helpers
+use eyre::{eyre, Result, WrapErr};
+use socket2::{Socket, SockRef, Domain, Type, Protocol};
+use std::net::{TcpListener, TcpStream, UdpSocket, SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::time::Duration;
+use crate::config::TestConfig;
+
+/// Maximum time to establish a TCP connection with the server
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// IP address family selection
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Family {
+    /// IPv4 and IPv6
+    #[default]
+    Any,
+    V4,
+    V6,
+}
+
+impl Family {
+    pub fn new(ipv4: bool, ipv6: bool) -> Self {
+        match (ipv4, ipv6) {
+            (true, false) => Family::V4,
+            (false, true) => Family::V6,
+            _ => Family::Any,
+        }
+    }
+
+    fn matches(&self, addr: &SocketAddr) -> bool {
+        match self {
+            Family::Any => true,
+            Family::V4 => addr.is_ipv4(),
+            Family::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+/// Resolve a hostname or an IP address into the socket addresses
+/// of the specified address family, in the resolver order.
+pub fn resolve(host: &str, port: u16, family: Family) -> Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = (host, port).to_socket_addrs()
+        .wrap_err_with(|| format!("Failed to resolve {}", host))?
+        .filter(|addr| family.matches(addr))
+        .collect();
+    if addrs.is_empty() {
+        return Err(eyre!("No {} address found for {}", match family {
+            Family::Any => "IP",
+            Family::V4 => "IPv4",
+            Family::V6 => "IPv6",
+        }, host));
+    }
+    Ok(addrs)
+}
+
+/// Apply the DSCP and the packet MARK configured by the client on a data socket
+pub fn set_data_options(socket: SockRef, config: &TestConfig) -> Result<()> {
+    if let Some(dscp) = config.dscp {
+        if !(0..=63).contains(&dscp) {
+            return Err(eyre!("Invalid DSCP value {}", dscp));
+        }
+        let tos = (dscp as u32) << 2;
+        let ipv6 = socket.local_addr()
+            .wrap_err("Failed to get socket address")?
+            .is_ipv6();
+        if ipv6 {
+            socket.set_tclass_v6(tos)
+                .wrap_err("Failed to set IPV6_TCLASS")?;
+            // IP_TOS is used for IPv4-mapped addresses on a dual-stack socket
+            let _ = socket.set_tos(tos);
+        }
+        else {
+            socket.set_tos(tos)
+                .wrap_err("Failed to set IP_TOS")?;
+        }
+    }
+    if let Some(mark) = config.mark {
+        socket.set_mark(mark)
+            .wrap_err("Failed to set SO_MARK")?;
+    }
+    Ok(())
+}
+
+/// Return the local address to bind for the specified destination
+fn bind_addr(addr: SocketAddr, bind: Option<&str>) -> Result<Option<SocketAddr>> {
+    let bind = match bind {
+        Some(bind) => bind,
+        None => {return Ok(None);},
+    };
+    let ip_addr = bind.parse::<IpAddr>()
+        .wrap_err_with(|| format!("Invalid bind address {}", bind))?;
+    if ip_addr.is_ipv4() != addr.is_ipv4() {
+        return Err(eyre!("Bind address {} does not match server address family", ip_addr));
+    }
+    Ok(Some(SocketAddr::new(ip_addr, 0)))
+}
+
+/// Connect a TCP stream to the server, from the client bind address.
+///
+/// The DSCP and the packet MARK of the test configuration are applied
+/// before connecting on data streams so that the TCP handshake is also classified.
+pub fn tcp_connect(addr: SocketAddr, bind: Option<&str>, config: Option<&TestConfig>) -> Result<TcpStream> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))
+        .wrap_err("Failed to create TCP socket")?;
+    if let Some(bind) = bind_addr(addr, bind)? {
+        socket.bind(&bind.into())
+            .wrap_err_with(|| format!("Failed to bind {}", bind))?;
+    }
+    if let Some(config) = config {
+        set_data_options(SockRef::from(&socket), config)?;
+    }
+    socket.connect_timeout(&addr.into(), CONNECT_TIMEOUT)
+        .wrap_err_with(|| format!("Failed to connect to server {}", addr))?;
+    Ok(socket.into())
+}
+
+/// Create an UDP data socket connected to the server, from the client bind address.
+pub fn udp_connect(addr: SocketAddr, bind: Option<&str>, config: &TestConfig) -> Result<UdpSocket> {
+    let bind = match bind_addr(addr, bind)? {
+        Some(bind) => bind,
+        None if addr.is_ipv4() => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        None => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
+    let socket = UdpSocket::bind(bind)
+        .wrap_err_with(|| format!("Failed to bind {}", bind))?;
+    set_data_options(SockRef::from(&socket), config)?;
+    socket.connect(addr)
+        .wrap_err("Failed to connect to server")?;
+    Ok(socket)
+}
+
+/// Restrict an IPv6 socket to IPv6, or allow IPv4-mapped addresses (dual-stack)
+fn set_v6only(socket: &Socket, addr: SocketAddr, v6only: bool) -> Result<()> {
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6only)
+            .wrap_err("Failed to set IPV6_V6ONLY")?;
+    }
+    Ok(())
+}
+
+/// Create a TCP listener bound to the specified address.
+///
+/// An IPv6 listener only accepts IPv6 clients if v6only is set,
+/// and also accepts IPv4 clients otherwise.
+pub fn tcp_listen(addr: SocketAddr, v6only: bool) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))
+        .wrap_err("Failed to create TCP socket")?;
+    socket.set_reuse_address(true)
+        .wrap_err("Failed to set SO_REUSEADDR")?;
+    set_v6only(&socket, addr, v6only)?;
+    socket.bind(&addr.into())
+        .wrap_err_with(|| format!("Failed to bind {}", addr))?;
+    socket.listen(128)
+        .wrap_err_with(|| format!("Failed to listen on {}", addr))?;
+    Ok(socket.into())
+}
+
+/// Create an UDP socket bound to the specified address.
+///
+/// SO_REUSEADDR is set so that the UDP listener and the connected
+/// UDP data streams can share the same server port.
+pub fn udp_bind(addr: SocketAddr, v6only: bool) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)
+        .wrap_err("Failed to create UDP socket")?;
+    socket.set_reuse_address(true)
+        .wrap_err("Failed to set SO_REUSEADDR")?;
+    set_v6only(&socket, addr, v6only)?;
+    socket.bind(&addr.into())
+        .wrap_err_with(|| format!("Failed to bind {}", addr))?;
+    Ok(socket.into())
+}
+
+/// Create an in-memory file holding the data sent by sendfile()
+pub fn memfd(data: &[u8]) -> Result<File> {
+    let fd = unsafe { libc::memfd_create(c"speednet".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error())
+            .wrap_err("Failed to create memfd");
+    }
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(data)
+        .wrap_err("Failed to fill memfd")?;
+    Ok(file)
+}
+
+/// Send the first len bytes of the file on the TCP stream,
+/// without copying them from user space.
+pub fn sendfile(stream: &TcpStream, file: &File, len: usize) -> io::Result<usize> {
+    let mut offset: libc::off_t = 0;
+    let sent = unsafe { libc::sendfile(stream.as_raw_fd(), file.as_raw_fd(), &mut offset, len) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+/// Set the TCP congestion control algorithm of a data stream
+pub fn set_congestion(socket: SockRef, congestion: &str) -> Result<()> {
+    socket.set_tcp_congestion(congestion.as_bytes())
+        .wrap_err_with(|| format!("Failed to set TCP congestion control {}", congestion))
+}
+
+/// Check that the TCP congestion control algorithm is available
+/// before starting the data streams.
+pub fn check_congestion(congestion: &str) -> Result<()> {
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))
+        .wrap_err("Failed to create TCP socket")?;
+    set_congestion(SockRef::from(&socket), congestion)
+}
+
+/// Return the TCP_INFO statistics of a TCP stream
+pub fn tcp_info(stream: &TcpStream) -> io::Result<libc::tcp_info> {
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void, &mut len)
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(info)
+}
@@ -0,0 +1,249 @@
+/// Human-friendly bandwidth and size units
+use serde::Deserialize;
+use serde::Serialize;
+use std::time::Duration;
+
+/// Units used to print the test results, as in iperf3
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum Format {
+    /// Kbits/sec
+    #[value(name = "k")]
+    Kbits,
+    /// Mbits/sec
+    #[value(name = "m")]
+    Mbits,
+    /// Gbits/sec
+    #[value(name = "g")]
+    Gbits,
+    /// KBytes/sec
+    #[value(name = "K")]
+    KBytes,
+    /// MBytes/sec
+    #[value(name = "M")]
+    MBytes,
+    /// GBytes/sec
+    #[value(name = "G")]
+    GBytes,
+}
+
+impl Format {
+    /// Return the unit index: 1 for Kilo, 2 for Mega and 3 for Giga
+    fn index(&self) -> u32 {
+        match self {
+            Format::Kbits | Format::KBytes => 1,
+            Format::Mbits | Format::MBytes => 2,
+            Format::Gbits | Format::GBytes => 3,
+        }
+    }
+
+    fn is_bytes(&self) -> bool {
+        matches!(self, Format::KBytes | Format::MBytes | Format::GBytes)
+    }
+}
+
+/// Parse a size or a rate with an optional unit suffix.
+///
+/// K, M, G and T are powers of 1000 while Ki, Mi, Gi and Ti are powers of 1024
+/// (e.g. `10M`, `2.5G`, `64K` or `1Ki`).
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, suffix) = value.split_at(split);
+
+    let number = number.parse::<f64>()
+        .map_err(|_| format!("Invalid number '{}'", value))?;
+    let multiplier: u64 = match suffix {
+        "" => 1,
+        "k" | "K" => 1000,
+        "m" | "M" => 1000 * 1000,
+        "g" | "G" => 1000 * 1000 * 1000,
+        "t" | "T" => 1000 * 1000 * 1000 * 1000,
+        "ki" | "Ki" => 1 << 10,
+        "mi" | "Mi" => 1 << 20,
+        "gi" | "Gi" => 1 << 30,
+        "ti" | "Ti" => 1 << 40,
+        _ => {return Err(format!("Invalid unit '{}' (expected K, M, G, T, Ki, Mi, Gi or Ti)", suffix));},
+    };
+
+    Ok((number * multiplier as f64).round() as u64)
+}
+
+/// Parse a duration in seconds with an optional `s` or `ms` unit suffix
+/// (e.g. `0.5`, `5s` or `100ms`).
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let (number, multiplier) = match value.strip_suffix("ms") {
+        Some(number) => (number, 0.001),
+        None => (value.strip_suffix('s').unwrap_or(value), 1.0),
+    };
+
+    let number = number.parse::<f64>()
+        .map_err(|_| format!("Invalid duration '{}'", value))?;
+    Duration::try_from_secs_f64(number * multiplier)
+        .map_err(|_| format!("Invalid duration '{}'", value))
+}
+
+/// Format a value with 3 significant digits, as in iperf3
+fn format_value(value: f64, unit: &str) -> String {
+    if value < 9.995 {
+        format!("{:.2} {}", value, unit)
+    }
+    else if value < 99.95 {
+        format!("{:.1} {}", value, unit)
+    }
+    else {
+        format!("{:.0} {}", value, unit)
+    }
+}
+
+/// Return the unit index to use to print value, when not forced by format
+fn auto_index(value: f64, base: f64) -> u32 {
+    let mut index = 0;
+    let mut value = value;
+    while value >= base && index < 3 {
+        value /= base;
+        index += 1;
+    }
+    index
+}
+
+/// Format a number of bytes (e.g. `112 MBytes`)
+pub fn format_bytes(bytes: u64, format: Option<Format>) -> String {
+    const UNITS: [&str; 4] = ["Bytes", "KBytes", "MBytes", "GBytes"];
+    let bytes = bytes as f64;
+    let index = match format {
+        Some(format) => format.index(),
+        None => auto_index(bytes, 1024.0),
+    };
+    format_value(bytes / 1024f64.powi(index as i32), UNITS[index as usize])
+}
+
+/// Format a rate in bits per second (e.g. `941 Mbits/sec`)
+pub fn format_rate(bits_per_second: u64, format: Option<Format>) -> String {
+    const BITS: [&str; 4] = ["bits/sec", "Kbits/sec", "Mbits/sec", "Gbits/sec"];
+    const BYTES: [&str; 4] = ["Bytes/sec", "KBytes/sec", "MBytes/sec", "GBytes/sec"];
+    let bits_per_second = bits_per_second as f64;
+    match format {
+        Some(format) if format.is_bytes() => {
+            let index = format.index();
+            format_value(bits_per_second / 8.0 / 1024f64.powi(index as i32), BYTES[index as usize])
+        },
+        Some(format) => {
+            let index = format.index();
+            format_value(bits_per_second / 1000f64.powi(index as i32), BITS[index as usize])
+        },
+        None => {
+            let index = auto_index(bits_per_second, 1000.0);
+            format_value(bits_per_second / 1000f64.powi(index as i32), BITS[index as usize])
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sizes() {
+        let sizes = [
+            ("0", 0),
+            ("1400", 1400),
+            (" 100 ", 100),
+            ("1K", 1000),
+            ("1k", 1000),
+            ("1Ki", 1024),
+            ("1.5Ki", 1536),
+            ("64K", 64000),
+            ("10M", 10000000),
+            ("1Mi", 1048576),
+            ("2.5G", 2500000000),
+            ("1Gi", 1073741824),
+            ("1T", 1000000000000),
+            ("1Ti", 1099511627776),
+            ("0.5", 1),
+        ];
+        for (value, expected) in sizes {
+            assert_eq!(parse_size(value), Ok(expected), "{}", value);
+        }
+    }
+
+    #[test]
+    fn parse_invalid_sizes() {
+        for value in ["", "K", ".", "-1", "1.2.3", "10X", "10KB", "1e3", "1 M"] {
+            assert!(parse_size(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn parse_durations() {
+        let durations = [
+            ("1", Duration::from_secs(1)),
+            ("0.5", Duration::from_millis(500)),
+            ("5s", Duration::from_secs(5)),
+            ("500ms", Duration::from_millis(500)),
+            ("0.1s", Duration::from_millis(100)),
+            ("1.5ms", Duration::from_micros(1500)),
+        ];
+        for (value, expected) in durations {
+            assert_eq!(parse_duration(value), Ok(expected), "{}", value);
+        }
+    }
+
+    #[test]
+    fn parse_invalid_durations() {
+        for value in ["", "s", "ms", "-1", "1m", "1h", "abc", "inf", "nan"] {
+            assert!(parse_duration(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn format_values() {
+        let values = [
+            (0.0, "0.00 B"),
+            (1.234, "1.23 B"),
+            (9.994, "9.99 B"),
+            (9.995, "10.0 B"),
+            (12.34, "12.3 B"),
+            (99.94, "99.9 B"),
+            (99.95, "100 B"),
+            (941.2, "941 B"),
+        ];
+        for (value, expected) in values {
+            assert_eq!(format_value(value, "B"), expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn format_sizes() {
+        let sizes = [
+            (0, None, "0.00 Bytes"),
+            (1023, None, "1023 Bytes"),
+            (1024, None, "1.00 KBytes"),
+            (117440512, None, "112 MBytes"),
+            (1 << 40, None, "1024 GBytes"),
+            (1 << 20, Some(Format::KBytes), "1024 KBytes"),
+            (1 << 20, Some(Format::Mbits), "1.00 MBytes"),
+        ];
+        for (bytes, format, expected) in sizes {
+            assert_eq!(format_bytes(bytes, format), expected, "{} {:?}", bytes, format);
+        }
+    }
+
+    #[test]
+    fn format_rates() {
+        let rates = [
+            (0, None, "0.00 bits/sec"),
+            (999, None, "999 bits/sec"),
+            (1000, None, "1.00 Kbits/sec"),
+            (941000000, None, "941 Mbits/sec"),
+            (2500000000, None, "2.50 Gbits/sec"),
+            (1000000000, Some(Format::Mbits), "1000 Mbits/sec"),
+            (8 << 20, Some(Format::MBytes), "1.00 MBytes/sec"),
+            (8 << 10, Some(Format::KBytes), "1.00 KBytes/sec"),
+        ];
+        for (bits_per_second, format, expected) in rates {
+            assert_eq!(format_rate(bits_per_second, format), expected, "{} {:?}", bits_per_second, format);
+        }
+    }
+}
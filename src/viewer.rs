@@ -0,0 +1,82 @@
+/// Draw speednet results in real-time with dataviewer
+///
+/// dataviewer is spawned as a child process and the samples are written
+/// on its standard input, one sample per line:
+///
+/// `<chart>/<serie> <x> <y>`
+///
+/// where x is the end of the interval in seconds.
+use eyre::{Result, WrapErr};
+use std::io::Write;
+use std::process::{Command, Child, ChildStdin, Stdio};
+use crate::aggregator::{IntervalStats, StreamStats};
+
+/// dataviewer program name, searched in PATH
+const DATAVIEWER: &str = "dataviewer";
+
+pub struct Viewer {
+    _child: Child,
+    stdin: Option<ChildStdin>,
+    udp: bool,
+}
+
+impl Viewer {
+    pub fn new(udp: bool) -> Result<Self> {
+        let mut child = Command::new(DATAVIEWER)
+            .stdin(Stdio::piped())
+            .spawn()
+            .wrap_err_with(|| format!("Failed to spawn {} (cargo install {})", DATAVIEWER, DATAVIEWER))?;
+        let stdin = child.stdin.take();
+
+        Ok(Self {
+            _child: child,
+            stdin,
+            udp,
+        })
+    }
+
+    fn write_sample(stdin: &mut ChildStdin, chart: &str, serie: &str, x: f64, y: f64) -> std::io::Result<()> {
+        writeln!(stdin, "{}/{} {:.3} {}", chart, serie, x, y)
+    }
+
+    fn write_stats(&self, stdin: &mut ChildStdin, serie: &str, stats: &StreamStats) -> std::io::Result<()> {
+        let mbps = stats.bits_per_second as f64 / 1000000.0;
+        Self::write_sample(stdin, "throughput", serie, stats.end, mbps)?;
+        if self.udp {
+            Self::write_sample(stdin, "loss", serie, stats.end, stats.lost_percent)?;
+            if let Some(jitter_ms) = stats.jitter_ms {
+                Self::write_sample(stdin, "jitter", serie, stats.end, jitter_ms)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Draw the statistics of all streams for the specified interval
+    pub fn push(&mut self, interval: &IntervalStats) -> Result<()> {
+        let mut stdin = match self.stdin.take() {
+            Some(stdin) => stdin,
+            None => {return Ok(());},
+        };
+
+        let mut result = Ok(());
+        for stats in &interval.streams {
+            let serie = stats.streamid.unwrap_or_default().to_string();
+            result = result.and_then(|_| self.write_stats(&mut stdin, &serie, stats));
+        }
+        if interval.streams.len() > 1 {
+            for sum in interval.sums() {
+                let serie = match interval.sum_upload.is_some() {
+                    true => format!("sum-{:?}", sum.direction).to_lowercase(),
+                    false => "sum".to_string(),
+                };
+                result = result.and_then(|_| self.write_stats(&mut stdin, &serie, sum));
+            }
+        }
+        result = result.and_then(|_| stdin.flush());
+
+        // Stop drawing if dataviewer was closed
+        result.wrap_err_with(|| format!("Failed to write to {}", DATAVIEWER))?;
+        self.stdin = Some(stdin);
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+//! Run speedtests between a client and a server started in-process
+use speednet::{ArgsServer, Client, Server, ServerHandle, StreamStats, TestConfig, TestResults};
+use std::sync::{Arc, Mutex};
+
+/// Test duration in seconds
+const TIME: u64 = 2;
+
+/// Bandwidth of each stream in bits per second
+const BANDWIDTH: u64 = 20_000_000;
+
+fn start_server() -> ServerHandle {
+    let args = ArgsServer {
+        bind: Some("127.0.0.1".into()),
+        port: 0,
+        ..Default::default()
+    };
+    Server::new(args).unwrap().start().unwrap()
+}
+
+fn run(config: TestConfig) -> TestResults {
+    let server = start_server();
+    let mut client = Client::connect(server.local_addr(), config).unwrap();
+    let results = client.run().unwrap();
+    drop(client);
+    server.stop().unwrap();
+    results
+}
+
+fn config(udp: bool, revert: bool) -> TestConfig {
+    TestConfig {
+        udp,
+        revert,
+        bandwidth: Some(BANDWIDTH),
+        time: TIME,
+        ..Default::default()
+    }
+}
+
+/// Check that the stream lasted the test duration at the configured bandwidth
+fn check_stats(stats: &StreamStats) {
+    let duration = stats.end - stats.start;
+    assert!((duration - TIME as f64).abs() < 0.05, "duration {:.3}s in {:?}", duration, stats);
+    let error = (stats.bits_per_second as f64 - BANDWIDTH as f64).abs() / BANDWIDTH as f64;
+    assert!(error < 0.05, "bitrate {} in {:?}", stats.bits_per_second, stats);
+}
+
+fn check_results(results: &TestResults) {
+    assert_eq!(results.intervals.len(), TIME as usize);
+    check_stats(&results.end.sum);
+    assert_eq!(results.server.len(), 1);
+    check_stats(&results.server[0]);
+}
+
+#[test]
+fn tcp_upload() {
+    let results = run(config(false, false));
+    check_results(&results);
+}
+
+#[test]
+fn tcp_download() {
+    let results = run(config(false, true));
+    check_results(&results);
+}
+
+#[test]
+fn udp_upload() {
+    let results = run(config(true, false));
+    check_results(&results);
+    assert_eq!(results.end.sum.lost, 0);
+}
+
+#[test]
+fn udp_download() {
+    let results = run(config(true, true));
+    check_results(&results);
+    assert_eq!(results.end.sum.lost, 0);
+    assert!(results.end.sum.jitter_ms.is_some());
+    assert!(!results.end.sum.offload);
+}
+
+#[test]
+fn udp_gso_upload() {
+    let results = run(TestConfig { gso: true, ..config(true, false) });
+    check_results(&results);
+    assert_eq!(results.end.sum.lost, 0);
+    assert!(results.end.sum.offload);
+}
+
+#[test]
+fn udp_gso_download() {
+    let results = run(TestConfig { gso: true, ..config(true, true) });
+    check_results(&results);
+    assert_eq!(results.end.sum.lost, 0);
+    // The datagrams share their timestamps: the jitter is not measured
+    assert_eq!(results.end.sum.jitter_ms, None);
+    assert_eq!(results.server[0].jitter_ms, None);
+    assert!(results.end.sum.offload);
+}
+
+/// Check that the client and the server measure the same bytes after the omitted period
+fn check_omit_bytes(udp: bool, revert: bool) {
+    let bytes = 2_800_000;
+    let results = run(TestConfig { omit: 1, bytes: Some(bytes), time: 0, ..config(udp, revert) });
+    assert_eq!(results.end.sum.bytes, bytes, "{:?}", results.end.sum);
+    assert_eq!(results.server[0].bytes, bytes, "{:?}", results.server[0]);
+}
+
+#[test]
+fn tcp_omit_upload() {
+    check_omit_bytes(false, false);
+}
+
+#[test]
+fn tcp_omit_download() {
+    check_omit_bytes(false, true);
+}
+
+#[test]
+fn udp_omit_download() {
+    check_omit_bytes(true, true);
+}
+
+/// Check that all the datagrams of a test bounded by a packets count
+/// are received, although they are sent more than a second apart
+fn check_low_rate_packets(revert: bool) {
+    let packets = 3;
+    let results = run(TestConfig { bandwidth: Some(8_000), packets: Some(packets), time: 0, ..config(true, revert) });
+    assert_eq!(results.end.sum.packets, packets, "{:?}", results.end.sum);
+    assert_eq!(results.end.sum.lost, 0, "{:?}", results.end.sum);
+    assert_eq!(results.server[0].packets, packets, "{:?}", results.server[0]);
+}
+
+#[test]
+fn udp_low_rate_packets_upload() {
+    check_low_rate_packets(false);
+}
+
+#[test]
+fn udp_low_rate_packets_download() {
+    check_low_rate_packets(true);
+}
+
+#[test]
+fn server_logger() {
+    let args = ArgsServer {
+        bind: Some("127.0.0.1".into()),
+        port: 0,
+        ..Default::default()
+    };
+    let logs = Arc::new(Mutex::new(vec!()));
+    let sink = logs.clone();
+    let server = Server::new(args).unwrap()
+        .with_logger(move |line| sink.lock().unwrap().push(line.to_string()))
+        .start().unwrap();
+    let config = TestConfig { time: 1, ..Default::default() };
+    let mut client = Client::connect(server.local_addr(), config).unwrap();
+    client.run().unwrap();
+    drop(client);
+    server.stop().unwrap();
+
+    let logs = logs.lock().unwrap();
+    assert!(logs[0].starts_with("speednet server listening on"), "{:?}", logs);
+    assert!(logs.iter().any(|line| line.ends_with(" Finished")), "{:?}", logs);
+}